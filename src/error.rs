@@ -0,0 +1,48 @@
+use std::{error::Error, fmt, io};
+
+// Everything the public Server API can fail with
+#[derive(Debug)]
+pub enum ServerError {
+    Bind(io::Error), // The listener could not be bound to the requested address
+    Accept(io::Error), // Accepting a connection failed
+    Decode(prost::DecodeError), // Bytes received were not a valid message
+    InvalidConfig(&'static str), // A constructor was given a setting the server can't work with
+    SelfTest(String), // The startup self-test got the wrong reply
+    Io(io::Error), // Any other socket error
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Bind(e) => write!(f, "Failed to bind listener: {}", e),
+            ServerError::Accept(e) => write!(f, "Failed to accept connection: {}", e),
+            ServerError::Decode(e) => write!(f, "Failed to decode message: {}", e),
+            ServerError::InvalidConfig(reason) => write!(f, "Invalid server configuration: {}", reason),
+            ServerError::SelfTest(reason) => write!(f, "Startup self-test failed: {}", reason),
+            ServerError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Bind(e) | ServerError::Accept(e) | ServerError::Io(e) => Some(e),
+            ServerError::Decode(e) => Some(e),
+            ServerError::InvalidConfig(_) | ServerError::SelfTest(_) => None,
+        }
+    }
+}
+
+// Lets internal code keep using ? on socket calls
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
+
+impl From<prost::DecodeError> for ServerError {
+    fn from(e: prost::DecodeError) -> Self {
+        ServerError::Decode(e)
+    }
+}
@@ -0,0 +1,221 @@
+use crate::message::{
+    client_message, server_message, AddResponse, ClientMessage, DivideResponse, EchoMessage, ErrorCode, ErrorResponse,
+    MultiplyResponse, RangeRequest, ReverseBytesResponse, ServerMessage, StatsCalcResponse, SubtractResponse,
+};
+use log::{error, warn};
+
+const MAX_RANGE_LEN: i64 = 1000; // Most responses a single RangeRequest may ask for
+
+// Turns one request into its responses, usually exactly one.
+// Connection-level messages (PingRequest, StreamEchoRequest, BroadcastMessage, StatsRequest, ServerInfoRequest, QuitRequest), the shared
+// key-value store (SetRequest, GetRequest) and empty messages are answered by the server itself and never reach a handler.
+pub trait MessageHandler {
+    fn handle(&self, msg: ClientMessage) -> Option<ServerMessage>;
+
+    // Turn one request into any number of responses, written to the client in order. This is what the server calls.
+    // Defaults to handle's reply, except that RangeRequest, which handle can't answer with several responses, gets the
+    // default expansion. Handlers that only implement handle therefore keep RangeRequest working.
+    fn handle_many(&self, msg: ClientMessage) -> Vec<ServerMessage> {
+        match &msg.message {
+            Some(client_message::Message::RangeRequest(range_request)) => range_responses(range_request),
+            _ => self.handle(msg).into_iter().collect(),
+        }
+    }
+
+    // Check a dry-run request without acting on it: Err with the error handle would reply with, Ok if it would succeed.
+    // Only called for dry runs, including of connection-level messages. Accepts everything unless overridden.
+    fn validate(&self, _msg: &ClientMessage) -> Result<(), ErrorResponse> {
+        Ok(())
+    }
+}
+
+// Handler used unless the server is given another one: Echo, Add, Subtract, Multiply, Divide, StatsCalc, ReverseBytes and Range
+pub struct DefaultHandler;
+
+impl MessageHandler for DefaultHandler {
+    fn handle(&self, msg: ClientMessage) -> Option<ServerMessage> {
+        let response = match msg.message {
+            //in case of echo message
+            Some(client_message::Message::EchoMessage(echo_message)) => {
+                server_message::Message::EchoMessage(echo_message) // Send back the echoed message
+            }
+            //in case of add request message
+            Some(client_message::Message::AddRequest(add_request)) => {
+                // Checked so an out-of-range sum becomes an error reply instead of a panic
+                match add_request.a.checked_add(add_request.b) {
+                    Some(result) => server_message::Message::AddResponse(AddResponse { result }),
+                    None => server_message::Message::ErrorResponse(overflow_error("AddRequest")),
+                }
+            }
+            //in case of subtract request message
+            Some(client_message::Message::SubtractRequest(subtract_request)) => {
+                match subtract_request.a.checked_sub(subtract_request.b) {
+                    Some(result) => server_message::Message::SubtractResponse(SubtractResponse { result }),
+                    None => server_message::Message::ErrorResponse(overflow_error("SubtractRequest")),
+                }
+            }
+            //in case of multiply request message
+            Some(client_message::Message::MultiplyRequest(multiply_request)) => {
+                // The product of two i32s always fits in an i64, so no overflow check is needed
+                let result = i64::from(multiply_request.a) * i64::from(multiply_request.b);
+                server_message::Message::MultiplyResponse(MultiplyResponse { result })
+            }
+            //in case of divide request message
+            Some(client_message::Message::DivideRequest(divide_request)) => {
+                // Checked so a zero divisor, or i32::MIN / -1, becomes an error reply instead of a panic
+                match divide_request.a.checked_div(divide_request.b) {
+                    Some(result) => server_message::Message::DivideResponse(DivideResponse { result }),
+                    None if divide_request.b == 0 => server_message::Message::ErrorResponse(division_by_zero_error()),
+                    None => server_message::Message::ErrorResponse(overflow_error("DivideRequest")),
+                }
+            }
+            //in case of stats calculation request
+            Some(client_message::Message::StatsCalcRequest(stats_request)) => {
+                match calculate_stats(&stats_request.values) {
+                    Some(stats_response) => server_message::Message::StatsCalcResponse(stats_response),
+                    None => server_message::Message::ErrorResponse(empty_list_error()),
+                }
+            }
+            //in case of reverse bytes request
+            Some(client_message::Message::ReverseBytesRequest(reverse_request)) => {
+                let mut data = reverse_request.data;
+                data.reverse(); // Byte order only, multi-byte characters are not kept together
+                server_message::Message::ReverseBytesResponse(ReverseBytesResponse { data })
+            }
+            Some(client_message::Message::RangeRequest(_)) => {
+                return None; // Has several responses, so handle_many answers it
+            }
+            Some(client_message::Message::PingRequest(_))
+            | Some(client_message::Message::StreamEchoRequest(_))
+            | Some(client_message::Message::BroadcastMessage(_))
+            | Some(client_message::Message::StatsRequest(_))
+            | Some(client_message::Message::ServerInfoRequest(_))
+            | Some(client_message::Message::QuitRequest(_))
+            | Some(client_message::Message::SetRequest(_))
+            | Some(client_message::Message::GetRequest(_)) => {
+                return None; // Answered by the server before handlers are consulted
+            }
+            None => {
+                error!("Received a ClientMessage with no message!");
+                return None;
+            }
+        };
+
+        Some(ServerMessage {
+            message: Some(response),
+            ..Default::default()
+        })
+    }
+
+    // The same checks handle makes, without computing anything
+    fn validate(&self, msg: &ClientMessage) -> Result<(), ErrorResponse> {
+        match &msg.message {
+            Some(client_message::Message::AddRequest(add_request)) if add_request.a.checked_add(add_request.b).is_none() => {
+                Err(overflow_error("AddRequest"))
+            }
+            Some(client_message::Message::SubtractRequest(subtract_request))
+                if subtract_request.a.checked_sub(subtract_request.b).is_none() =>
+            {
+                Err(overflow_error("SubtractRequest"))
+            }
+            Some(client_message::Message::DivideRequest(divide_request)) if divide_request.b == 0 => {
+                Err(division_by_zero_error())
+            }
+            Some(client_message::Message::DivideRequest(divide_request))
+                if divide_request.a.checked_div(divide_request.b).is_none() =>
+            {
+                Err(overflow_error("DivideRequest"))
+            }
+            Some(client_message::Message::StatsCalcRequest(stats_request)) if stats_request.values.is_empty() => {
+                Err(empty_list_error())
+            }
+            Some(client_message::Message::RangeRequest(range_request)) => check_range(range_request),
+            None => Err(ErrorResponse {
+                code: ErrorCode::EmptyMessage.into(), // Same error the server sends when an empty message is run
+                message: "ClientMessage contained no request".to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+// A RangeRequest becomes one EchoMessage per value, or a single error if the range is out of bounds
+fn range_responses(range_request: &RangeRequest) -> Vec<ServerMessage> {
+    let responses = match check_range(range_request) {
+        Ok(()) => (range_request.start..=range_request.end)
+            .map(|value| server_message::Message::EchoMessage(EchoMessage {
+                content: value.to_string(),
+            }))
+            .collect(),
+        Err(error) => vec![server_message::Message::ErrorResponse(error)],
+    };
+    responses
+        .into_iter()
+        .map(|response| ServerMessage {
+            message: Some(response),
+            ..Default::default()
+        })
+        .collect()
+}
+
+// Error reply for arithmetic whose result does not fit the response type
+fn overflow_error(request: &str) -> ErrorResponse {
+    warn!("{} overflowed, sending error response", request);
+    ErrorResponse {
+        code: ErrorCode::Overflow.into(),
+        message: format!("{} result is out of range", request),
+    }
+}
+
+// Error reply for a DivideRequest with a zero divisor
+fn division_by_zero_error() -> ErrorResponse {
+    warn!("DivideRequest divided by zero, sending error response");
+    ErrorResponse {
+        code: ErrorCode::DivisionByZero.into(),
+        message: "DivideRequest divisor must not be zero".to_string(),
+    }
+}
+
+// Refuse a range that is backwards or longer than MAX_RANGE_LEN, rather than answering with nothing or flooding the client
+fn check_range(range_request: &RangeRequest) -> Result<(), ErrorResponse> {
+    let len = i64::from(range_request.end) - i64::from(range_request.start) + 1;
+    if (1..=MAX_RANGE_LEN).contains(&len) {
+        return Ok(());
+    }
+    warn!("RangeRequest {}..={} is out of bounds, sending error response", range_request.start, range_request.end);
+    Err(ErrorResponse {
+        code: ErrorCode::InvalidRange.into(),
+        message: format!("RangeRequest must cover between 1 and {} values", MAX_RANGE_LEN),
+    })
+}
+
+// Error reply for a StatsCalcRequest with nothing to calculate
+fn empty_list_error() -> ErrorResponse {
+    ErrorResponse {
+        code: ErrorCode::EmptyList.into(),
+        message: "StatsCalcRequest needs at least one value".to_string(),
+    }
+}
+
+// Mean and sample standard deviation of the values, None for an empty list.
+// A single value has no spread, so its standard deviation is reported as 0.
+fn calculate_stats(values: &[f64]) -> Option<StatsCalcResponse> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let count = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / count;
+    let stddev = if values.len() > 1 {
+        let squared_diffs: f64 = values.iter().map(|value| (value - mean).powi(2)).sum();
+        (squared_diffs / (count - 1.0)).sqrt()
+    } else {
+        0.0
+    };
+
+    Some(StatsCalcResponse {
+        mean,
+        stddev,
+        count: values.len() as u32,
+    })
+}
@@ -1,4 +1,8 @@
 pub mod server;
+pub mod handler;
+pub mod error;
+mod semaphore;
+mod stream;
 
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
@@ -0,0 +1,48 @@
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+// Counting semaphore used to cap how many requests are processed at once
+pub struct Semaphore {
+    available: Mutex<usize>, // Permits not currently held
+    released: Condvar, // Signalled whenever a permit is returned
+}
+
+// Holds one permit and gives it back when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    // Wait up to the timeout for a permit, None if none became free in time
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<SemaphorePermit<'_>> {
+        let deadline = Instant::now() + timeout;
+        let mut available = self.available.lock().unwrap();
+
+        while *available == 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            available = self.released.wait_timeout(available, remaining).unwrap().0;
+        }
+
+        *available -= 1;
+        Some(SemaphorePermit { semaphore: self })
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one(); // Wake one waiting request
+    }
+}
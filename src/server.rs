@@ -1,36 +1,315 @@
-use crate::message::{AddResponse, server_message, ClientMessage, client_message, ServerMessage};
-use log::{error, info, warn};
+use crate::message::{
+    AckResponse, BroadcastMessage, EchoMessage, ErrorCode, ErrorResponse, GetResponse, PingRequest, PleaseReconnect, PongResponse,
+    ProgressMessage, QuitResponse, ServerInfoResponse, SetResponse, StatsResponse, TimingBreakdown,
+    server_message,
+    ClientMessage, client_message, ServerMessage,
+};
+use crate::error::ServerError;
+use crate::handler::{DefaultHandler, MessageHandler};
+use crate::semaphore::Semaphore;
+use crate::stream::{Listener, Stream};
+#[cfg(unix)]
+use std::{os::unix::net::UnixListener, path::PathBuf};
+use log::{debug, error, info, warn};
 use prost::Message;
 use std::{
+    any::Any,
+    collections::HashMap,
     io::{self, ErrorKind, Read, Write},
-    net::{TcpListener, TcpStream},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener},
+    panic::{self, AssertUnwindSafe},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
         Arc, Mutex,
     },
-    thread,
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+const FRAME_HEADER_LEN: usize = 4; // Every message on the wire is preceded by its length as a big-endian u32
+const DEFAULT_BUFFER_SIZE: usize = 512; // Read buffer size used by Server::new
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024; // Largest payload a client may declare unless configured otherwise
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(100); // How long a read blocks before re-checking shutdown
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(10); // How long a write may block on a client that isn't reading
+const ACCESS_LOG_TARGET: &str = "access"; // Log target used for access-log lines
+const MAX_STREAM_ECHO_COUNT: u32 = 1000; // Most echoes a single StreamEchoRequest may ask for
+const MAX_STREAM_ECHO_INTERVAL: Duration = Duration::from_secs(10); // Longest pause a StreamEchoRequest may ask for between echoes
+const MAX_EMPTY_MESSAGES: u32 = 10; // Empty ClientMessages a connection may send before it is closed
+const SELF_TEST_NONCE: u64 = 0x5e1f_7e57; // Nonce the startup self-test expects back in its PongResponse
+const SELF_TEST_CONN_ID: ConnId = ConnId::MAX; // Id the startup self-test's probe is served under, never given to a real connection
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(2); // How long the startup self-test waits to connect and for its pong
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(1); // Pause after a transient accept error, like a peer aborting mid-handshake
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(10); // Pause after an unexpected accept error, and the first backoff step
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1); // Longest pause while out of descriptors or memory
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10); // Pause between checks when waiting on shared state unless configured
+const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(5); // How long stop waits for each client thread unless configured
+
+// Id the server gives each accepted connection, as used in logs and ConnectionMetrics
+pub type ConnId = u64;
+
+// What to do with a connection accepted while every pool worker is busy and the worker queue is at its cap
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SaturationPolicy {
+    #[default]
+    Reject, // Send SERVER_FULL and close it
+    Block, // Stop accepting until the queue has room, leaving new connections in the listen backlog
+    Burst(usize), // Serve it on an extra thread outside the pool, up to this many at once, then reject
+}
+
+// Settings shared by the server and every client it spawns
+#[derive(Clone, Copy)]
+struct ServerConfig {
+    buffer_size: usize, // Size of the buffer used for each read
+    read_timeout: Duration, // How long a blocking read waits before checking is_running
+    write_timeout: Duration, // How long a write may block on a full socket before the client is dropped as too slow
+    max_concurrent_requests: Option<usize>, // Server-wide cap on requests processed at once, None for no cap
+    request_wait_timeout: Duration, // How long a request waits for a free slot before being rejected as OVERLOADED
+    progress_interval: Option<u64>, // Send a ProgressMessage every this many frames, None to never send one
+    access_log: bool, // Emit one access-log line per completed request
+    workers: Option<usize>, // Service connections on this many pooled threads, None for a thread per client
+    read_ahead_limit: Option<usize>, // Most unprocessed bytes buffered per connection beyond the frame in hand, None for no cap
+    idle_timeout: Option<Duration>, // Close a client that sends no complete message for this long, None to wait forever
+    self_test: bool, // Ping the server over loopback in run before accepting connections
+    max_lifetime_connections: Option<u64>, // Serve this many connections in total, then drain and stop, None for no limit
+    max_message_size: usize, // Largest payload length a frame header may declare before the connection is dropped
+    frame_timeout: Option<Duration>, // Close a client whose partial frame stays incomplete this long, None to wait forever
+    max_connections: Option<usize>, // Most clients served or waiting for a worker at once, None for no cap
+    max_buffered_bytes: Option<usize>, // Refuse connections and shed idle ones while clients hold this many unprocessed bytes in total
+    rate_limit: Option<u32>, // Most messages processed per second on one connection, None for no limit
+    saturation_policy: SaturationPolicy, // What happens to a connection accepted while the worker queue is full
+    nodelay: bool, // Set TCP_NODELAY on client sockets so small replies aren't held back by Nagle's algorithm
+    join_timeout: Duration, // How long stop waits for each client or worker thread before leaving it running
+    poll_interval: Duration, // Pause between checks while draining, or while a full worker queue blocks accepting
+    #[cfg(unix)]
+    reuse_addr: bool, // Bind TCP listeners with SO_REUSEADDR, so a restart can take a port still held in TIME_WAIT
+    #[cfg(feature = "accept-delay")]
+    accept_delay: Duration, // Pause after each accept before serving it, to simulate slow connection setup in tests
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            max_concurrent_requests: None,
+            request_wait_timeout: Duration::ZERO,
+            progress_interval: None,
+            access_log: false,
+            workers: None,
+            read_ahead_limit: None,
+            idle_timeout: None,
+            self_test: false,
+            max_lifetime_connections: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            frame_timeout: None,
+            max_connections: None,
+            saturation_policy: SaturationPolicy::Reject,
+            rate_limit: None,
+            max_buffered_bytes: None,
+            nodelay: true,
+            join_timeout: DEFAULT_JOIN_TIMEOUT,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            #[cfg(unix)]
+            reuse_addr: true,
+            #[cfg(feature = "accept-delay")]
+            accept_delay: Duration::ZERO,
+        }
+    }
+}
+
 // Represents a connected client
 struct Client {
-    stream: TcpStream, // The TCP connection for the client
+    stream: Stream, // The connection to the client, TCP or Unix socket
+    pending: Vec<u8>, // Bytes received but not yet consumed as a complete frame
+    config: ServerConfig, // Settings inherited from the server
+    is_running: Arc<AtomicBool>, // Server running flag, checked whenever a read times out
+    request_slots: Option<Arc<Semaphore>>, // Server-wide in-flight request limit, shared by all clients
+    operation_slots: Arc<HashMap<&'static str, Semaphore>>, // In-flight limits for individual request types, shared by all clients
+    frames_received: u64, // Frames received on this connection so far
+    messages_decoded: u64, // Frames that decoded into a ClientMessage, reported by StatsRequest
+    empty_messages: u32, // ClientMessages received with no request in them
+    client_id: ConnId, // Server-assigned connection id
+    peer_addr: SocketAddr, // Address of the connected client
+    log_context: String, // "conn=<id> peer=<addr>", prefixed to every log line about this connection
+    response_bytes: usize, // Bytes written in response to the request being processed
+    peak_read_ahead: Arc<AtomicUsize>, // Server-wide high-water mark of unprocessed bytes buffered by one client
+    quiesce: Arc<AtomicBool>, // Set by Server::quiesce_client to recycle just this connection
+    cancelled: Arc<AtomicBool>, // Set by stop once the drain window is over, so a long request gives up
+    inbox: mpsc::Receiver<Arc<[u8]>>, // Encoded messages other clients broadcast to this one, written between requests
+    clients: Arc<Mutex<HashMap<ConnId, ClientEntry>>>, // Every live connection, for fanning out broadcasts
+    broadcasts_encoded: Arc<AtomicU64>, // Server-wide count of broadcast payloads encoded
+    store: Arc<Mutex<HashMap<String, String>>>, // Key-value store shared by every client
+    last_activity: Instant, // When the last complete message arrived, or when the client connected
+    frame_started: Option<Instant>, // When the first byte of the still incomplete frame arrived, None if nothing is buffered
+    handler: Arc<dyn MessageHandler + Send + Sync>, // Answers every request the connection doesn't handle itself
+    timing: Option<RequestTiming>, // Set while processing a request that asked for a timing breakdown
+    request_id: u64, // Id of the request being processed, echoed in its responses
+    rate_limiter: Option<TokenBucket>, // Paces message processing when the server has a rate limit
+    counters: Arc<ConnectionCounters>, // This connection's traffic, readable by Server::connection_metrics_snapshot
+    server_started: Instant, // When the server was created, for the uptime in ServerInfoResponse
+}
+
+// Token bucket holding up to a second's worth of messages, refilled continuously
+struct TokenBucket {
+    rate: f64, // Tokens added per second, and the bucket's capacity
+    tokens: f64, // Messages that may be processed right now without waiting
+    refilled: Instant, // When tokens was last brought up to date
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        TokenBucket {
+            rate: f64::from(rate),
+            tokens: f64::from(rate), // Start full, so a client's first second isn't throttled
+            refilled: Instant::now(),
+        }
+    }
+
+    // Take a token if one is available, otherwise how long until one will be
+    fn take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * self.rate).min(self.rate);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+// Timings of the request being processed, for the breakdown attached to its responses
+struct RequestTiming {
+    decode_ns: u64, // How long the request took to decode
+    handler_started: Instant, // When decoding finished and handling began
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Self {
-        stream.set_nonblocking(true).unwrap(); // Set the TCP stream to non-blocking mode
-        Client { stream }
+    pub fn new(
+        stream: Stream,
+        client_id: ConnId,
+        peer_addr: SocketAddr,
+        context: &ClientContext,
+        registration: Registration,
+    ) -> Result<Self, ServerError> {
+        stream.set_nonblocking(false)?; // Blocking reads, so idle clients don't spin
+        stream.set_read_timeout(Some(context.config.read_timeout))?; // Wake up periodically to notice shutdown
+        stream.set_write_timeout(Some(context.config.write_timeout))?; // Never block forever on a client that stopped reading
+        stream.set_nodelay(context.config.nodelay)?;
+        Ok(Client {
+            stream,
+            pending: Vec::new(),
+            config: context.config,
+            is_running: Arc::clone(&context.is_running),
+            request_slots: context.request_slots.clone(),
+            operation_slots: Arc::clone(&context.operation_slots),
+            frames_received: 0,
+            messages_decoded: 0,
+            empty_messages: 0,
+            client_id,
+            peer_addr,
+            log_context: format!("conn={} peer={}", client_id, peer_addr),
+            response_bytes: 0,
+            peak_read_ahead: Arc::clone(&context.peak_read_ahead),
+            quiesce: registration.quiesce,
+            cancelled: registration.cancelled,
+            inbox: registration.inbox,
+            clients: Arc::clone(&context.clients),
+            broadcasts_encoded: Arc::clone(&context.broadcasts_encoded),
+            store: Arc::clone(&context.store),
+            last_activity: Instant::now(),
+            frame_started: None,
+            handler: Arc::clone(&context.handler),
+            timing: None,
+            request_id: 0,
+            rate_limiter: context.config.rate_limit.map(TokenBucket::new),
+            counters: registration.counters,
+            server_started: context.started_at,
+        })
     }
 
     pub fn handle(&mut self) -> io::Result<()> {
-        let mut buffer = [0; 512]; // Buffer to hold incoming data
+        let mut buffer = vec![0; self.config.buffer_size]; // Buffer to hold incoming data
 
         loop {
-            let bytes_read = match self.stream.read(&mut buffer) {
+            // Process every complete frame already buffered before reading more
+            loop {
+                let payload = match self.next_frame() {
+                    Ok(Some(payload)) => payload,
+                    Ok(None) => break, // Wait for more bytes
+                    Err(declared) => {
+                        warn!(
+                            "[{}] Client declared a {} byte message, over the {} byte limit; closing connection.",
+                            self.log_context, declared, self.config.max_message_size
+                        );
+                        return Ok(()); // Never decode, or keep buffering towards, a frame that large
+                    }
+                };
+                self.frames_received += 1;
+                if !self.wait_for_rate_limit() {
+                    info!("[{}] Server stopping, closing client connection.", self.log_context);
+                    return Ok(());
+                }
+
+                if !self.process_message(&payload)? {
+                    info!("[{}] Closing connection after its last response.", self.log_context);
+                    return Ok(()); // Client asked for a one-shot request/response
+                }
+                self.last_activity = Instant::now(); // Measured from the reply, so slow requests don't count as idle
+                self.counters.touch();
+                self.frame_started = None; // Any bytes left over belong to a frame that starts now
+
+                self.send_progress_if_due()?;
+                if self.quiesce_if_requested()? {
+                    return Ok(()); // Recycled between requests, anything still buffered is left unanswered
+                }
+                if !self.is_running.load(Ordering::SeqCst) {
+                    info!("[{}] Server stopping, closing client connection after its current request.", self.log_context);
+                    return Ok(()); // Draining: finish what is in flight but take nothing new
+                }
+            }
+
+            if self.quiesce_if_requested()? {
+                return Ok(());
+            }
+
+            self.deliver_broadcasts()?;
+
+            if self.pending.is_empty() {
+                self.frame_started = None;
+            } else if let Some(frame_timeout) = self.config.frame_timeout {
+                let started = *self.frame_started.get_or_insert_with(Instant::now);
+                if started.elapsed() >= frame_timeout {
+                    warn!(
+                        "[{}] Client left a frame incomplete for {:?} with {} bytes received, closing connection.",
+                        self.log_context,
+                        frame_timeout,
+                        self.pending.len()
+                    );
+                    return Ok(()); // Dripping bytes doesn't restart the clock, so a slowloris can't hold the slot
+                }
+            }
+
+            if let Some(idle_timeout) = self.config.idle_timeout {
+                if self.last_activity.elapsed() >= idle_timeout {
+                    info!("[{}] Client idle for {:?}, closing connection.", self.log_context, idle_timeout);
+                    return Ok(()); // Partial frames don't count as activity, so a trickling client is closed too
+                }
+            }
+
+            let read_limit = self.read_limit(buffer.len());
+            let bytes_read = match self.stream.read(&mut buffer[..read_limit]) {
                 Ok(bytes) => bytes, // Successfully read some bytes
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(10)); // Wait briefly before retrying
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    // Read timed out with no data, keep waiting unless the server is stopping
+                    if !self.is_running.load(Ordering::SeqCst) {
+                        info!("[{}] Server stopping, closing client connection.", self.log_context);
+                        return Ok(());
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -39,128 +318,1918 @@ impl Client {
             };
 
             if bytes_read == 0 {
-                info!("Client disconnected.");
+                if self.pending.is_empty() {
+                    info!("[{}] Client disconnected.", self.log_context);
+                } else {
+                    // A partial header or payload can never complete now, so drop it quietly
+                    debug!("[{}] Client disconnected mid-frame with {} bytes unread.", self.log_context, self.pending.len());
+                }
                 return Ok(()); // Connection closed by the client
             }
 
-            match ClientMessage::decode(&buffer[..bytes_read]) {
-                Ok(client_message) => match client_message.message {
-                    //in case of echo message
-                    Some(client_message::Message::EchoMessage(echo_message)) => {
-                        info!("Received EchoMessage: {}", echo_message.content);
+            self.pending.extend_from_slice(&buffer[..bytes_read]); // Keep partial frames for the next read
+            self.counters.add_bytes_in(bytes_read);
+            self.counters.buffered_bytes.store(self.pending.len(), Ordering::SeqCst);
+            self.peak_read_ahead.fetch_max(self.pending.len(), Ordering::SeqCst);
+        }
+    }
+
+    // Write out everything other clients have broadcast since the last check
+    fn deliver_broadcasts(&mut self) -> io::Result<()> {
+        while let Ok(payload) = self.inbox.try_recv() {
+            self.write_payload(&payload)?;
+        }
+        Ok(())
+    }
+
+    // Queue a broadcast for every other live client. Sending never blocks, each recipient writes it from its own thread.
+    // The message is encoded once and every recipient writes the same shared bytes.
+    fn broadcast(&self, broadcast: BroadcastMessage) {
+        let payload: Arc<[u8]> = ServerMessage {
+            message: Some(server_message::Message::BroadcastMessage(broadcast)),
+            ..Default::default()
+        }
+        .encode_to_vec()
+        .into();
+        self.broadcasts_encoded.fetch_add(1, Ordering::SeqCst);
+
+        let clients = self.clients.lock().unwrap();
+        for (client_id, client) in clients.iter() {
+            if *client_id != self.client_id {
+                let _ = client.outbox.send(Arc::clone(&payload)); // Recipient may be on its way out
+            }
+        }
+    }
+
+    // Hold the next message back until the rate limit allows it, false if the server stopped meanwhile
+    fn wait_for_rate_limit(&mut self) -> bool {
+        let Some(bucket) = self.rate_limiter.as_mut() else {
+            return true;
+        };
+        while let Err(wait) = bucket.take() {
+            debug!("[{}] Client over its rate limit, delaying for {:?}.", self.log_context, wait);
+            if !self.is_running.load(Ordering::SeqCst) {
+                return false;
+            }
+            thread::sleep(wait.min(self.config.read_timeout)); // Wake up to notice shutdown
+        }
+        true
+    }
 
-                        let payload = ServerMessage {
-                            message: Some(server_message::Message::EchoMessage(echo_message)),
-                        }
-                        .encode_to_vec();
+    // Wait out the pause between streamed responses, in slices so that a recycle or the end of the drain window
+    // is noticed. False if the request should stop early.
+    fn pace(&self, interval: Duration) -> bool {
+        let deadline = Instant::now() + interval;
+        loop {
+            if self.quiesce.load(Ordering::SeqCst) || self.cancelled.load(Ordering::SeqCst) {
+                return false;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            thread::sleep(left.min(self.config.read_timeout));
+        }
+    }
+
+    // Tell the client to reconnect if the server asked to recycle this connection, true if it did
+    fn quiesce_if_requested(&mut self) -> io::Result<bool> {
+        if !self.quiesce.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+
+        info!("[{}] Quiescing client, asking it to reconnect.", self.log_context);
+        self.send_response(server_message::Message::PleaseReconnect(PleaseReconnect {
+            reason: "Connection is being recycled".to_string(),
+        }))?;
+        Ok(true)
+    }
+
+    // How many bytes the next read may take without going past the read-ahead limit.
+    // A frame larger than the limit may still be buffered whole, but nothing beyond it.
+    fn read_limit(&self, buffer_len: usize) -> usize {
+        match self.config.read_ahead_limit {
+            Some(limit) => {
+                let frame_len = read_header(&self.pending).map_or(0, |declared| FRAME_HEADER_LEN + declared); // 0 until the header is in
+                let allowed = limit.max(frame_len).saturating_sub(self.pending.len());
+                allowed.clamp(1, buffer_len) // Always read something so the frame in hand can complete
+            }
+            None => buffer_len,
+        }
+    }
+
+    // Decode one message and send its response(s), false if the connection should close afterwards
+    fn process_message(&mut self, payload: &[u8]) -> io::Result<bool> {
+        let started = Instant::now();
+        self.response_bytes = 0;
+
+        let client_message = match ClientMessage::decode(payload) {
+            Ok(client_message) => client_message,
+            Err(e) if is_invalid_utf8(&e) => {
+                // The client's fault rather than garbled framing, so tell it what was wrong and keep the connection
+                warn!("[{}] ClientMessage has a string that is not valid UTF-8: {}", self.log_context, e);
+                self.send_response(server_message::Message::ErrorResponse(ErrorResponse {
+                    code: ErrorCode::InvalidUtf8.into(),
+                    message: format!("ClientMessage contains a string that is not valid UTF-8 ({})", e),
+                }))?;
+                return Ok(true);
+            }
+            Err(e) => {
+                error!("[{}] Failed to decode ClientMessage: {}", self.log_context, e); // Log decoding errors
+                return Ok(true);
+            }
+        };
+        self.messages_decoded += 1;
+        self.counters.add_message();
+        self.timing = client_message.include_timing.then(|| RequestTiming {
+            decode_ns: started.elapsed().as_nanos() as u64,
+            handler_started: Instant::now(),
+        });
+
+        self.request_id = client_message.request_id;
+
+        let request_type = message_type(&client_message.message);
+        let keep_open = self.dispatch(client_message);
+        self.timing = None; // Responses sent outside a request, like broadcasts, carry no breakdown
+        self.request_id = 0;
+        let keep_open = keep_open?;
+
+        if self.config.access_log {
+            // One audit line per completed request, separate from the debug logging
+            info!(
+                target: ACCESS_LOG_TARGET,
+                "conn={} peer={} type={} request_bytes={} response_bytes={} duration_us={}",
+                self.client_id,
+                self.peer_addr,
+                request_type,
+                FRAME_HEADER_LEN + payload.len(),
+                self.response_bytes,
+                started.elapsed().as_micros()
+            );
+        }
+
+        Ok(keep_open)
+    }
+
+    // Run a decoded message and send its response(s), false if the connection should close afterwards
+    fn dispatch(&mut self, client_message: ClientMessage) -> io::Result<bool> {
+        let keep_open = !client_message.close_after_response; // One-shot clients ask to be closed after the reply
+
+        if client_message.dry_run {
+            debug!("[{}] Dry run of {}", self.log_context, message_type(&client_message.message));
+            let response = match self.handler.validate(&client_message) {
+                Ok(()) => server_message::Message::AckResponse(AckResponse {}),
+                Err(error) => server_message::Message::ErrorResponse(error),
+            };
+            self.send_response(response)?; // Nothing was executed, stored or broadcast
+            return Ok(keep_open);
+        }
+
+        // Hold a server-wide request slot while the request is processed, if the server caps them
+        let request_slots = self.request_slots.clone();
+        let _permit = match request_slots.as_deref() {
+            Some(slots) => match slots.acquire_timeout(self.config.request_wait_timeout) {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!("[{}] Server overloaded, rejecting request.", self.log_context);
+                    self.send_overloaded("Too many requests in flight, try again later")?;
+                    return Ok(keep_open);
+                }
+            },
+            None => None,
+        };
+
+        // And a slot for this kind of request, if it has a limit of its own
+        let operation = message_type(&client_message.message);
+        let operation_slots = Arc::clone(&self.operation_slots);
+        let _operation_permit = match operation_slots.get(operation) {
+            Some(slots) => match slots.acquire_timeout(self.config.request_wait_timeout) {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!("[{}] Too many {} requests in flight, rejecting request.", self.log_context, operation);
+                    self.send_overloaded(&format!("Too many {} requests in flight, try again later", operation))?;
+                    return Ok(keep_open);
+                }
+            },
+            None => None,
+        };
+
+        match client_message.message {
+            //in case of ping request
+            Some(client_message::Message::PingRequest(ping_request)) => {
+                debug!("[{}] Received PingRequest: {}", self.log_context, ping_request.nonce);
+
+                self.send_response(server_message::Message::PongResponse(PongResponse {
+                    nonce: ping_request.nonce,
+                }))?; // Answer the health check with the same nonce
+            }
+            //in case of stream echo request
+            Some(client_message::Message::StreamEchoRequest(stream_request)) => {
+                info!(
+                    "[{}] Received StreamEchoRequest: {} x{} every {}ms",
+                    self.log_context, stream_request.content, stream_request.count, stream_request.interval_ms
+                );
+
+                let interval = Duration::from_millis(u64::from(stream_request.interval_ms));
+                if stream_request.count > MAX_STREAM_ECHO_COUNT || interval > MAX_STREAM_ECHO_INTERVAL {
+                    // Refused rather than clamped, so one request can't hold the connection's thread for long
+                    warn!("[{}] StreamEchoRequest is out of bounds, sending error response", self.log_context);
+                    self.send_response(server_message::Message::ErrorResponse(ErrorResponse {
+                        code: ErrorCode::InvalidRange.into(),
+                        message: format!(
+                            "StreamEchoRequest may ask for at most {} echoes, at most {}ms apart",
+                            MAX_STREAM_ECHO_COUNT,
+                            MAX_STREAM_ECHO_INTERVAL.as_millis()
+                        ),
+                    }))?;
+                    return Ok(keep_open);
+                }
 
-                        self.stream.write_all(&payload)?; // Send back the echoed message
-                        self.stream.flush()?; // Ensure the message is sent immediately
+                for i in 0..stream_request.count {
+                    if i > 0 && !self.pace(interval) {
+                        info!("[{}] StreamEchoRequest stopped after {} echoes.", self.log_context, i);
+                        break; // The rest of the stream is dropped, the connection is on its way out
                     }
-                    //in case of add request message
-                    Some(client_message::Message::AddRequest(add_request)) => {
-                        info!("Received AddRequest: {} + {}", add_request.a, add_request.b);
 
-                        let result = add_request.a + add_request.b; // Perform addition
-                        let add_response = AddResponse { result };
+                    let echo_message = EchoMessage {
+                        content: stream_request.content.clone(),
+                    };
+                    self.send_response(server_message::Message::EchoMessage(echo_message))?; // Send each echo as its own response
+                }
+            }
+            //in case of quit request
+            Some(client_message::Message::QuitRequest(_)) => {
+                info!("[{}] Client quit.", self.log_context);
+
+                self.send_response(server_message::Message::QuitResponse(QuitResponse {}))?;
+                return Ok(false); // Close from our side once the client has its acknowledgment
+            }
+            //in case of stats request
+            Some(client_message::Message::StatsRequest(_)) => {
+                debug!("[{}] Received StatsRequest", self.log_context);
 
-                        let payload = ServerMessage {
-                            message: Some(server_message::Message::AddResponse(add_response)),
-                        }
-                        .encode_to_vec();
+                self.send_response(server_message::Message::StatsResponse(StatsResponse {
+                    message_count: self.messages_decoded, // Already counts this request
+                }))?;
+            }
+            //in case of server info request
+            Some(client_message::Message::ServerInfoRequest(_)) => {
+                debug!("[{}] Received ServerInfoRequest", self.log_context);
+
+                let active_connections = self.clients.lock().unwrap().len() as u32;
+                self.send_response(server_message::Message::ServerInfoResponse(ServerInfoResponse {
+                    uptime_secs: self.server_started.elapsed().as_secs(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    active_connections,
+                }))?;
+            }
+            //in case of set request
+            Some(client_message::Message::SetRequest(set_request)) => {
+                debug!("[{}] Received SetRequest for key {}", self.log_context, set_request.key);
+
+                self.store.lock().unwrap().insert(set_request.key, set_request.value);
+                self.send_response(server_message::Message::SetResponse(SetResponse {}))?; // Stored, visible to every client
+            }
+            //in case of get request
+            Some(client_message::Message::GetRequest(get_request)) => {
+                debug!("[{}] Received GetRequest for key {}", self.log_context, get_request.key);
+
+                let value = self.store.lock().unwrap().get(&get_request.key).cloned();
+                self.send_response(server_message::Message::GetResponse(GetResponse { value }))?;
+            }
+            //in case of broadcast message
+            Some(client_message::Message::BroadcastMessage(broadcast)) => {
+                info!("[{}] Received BroadcastMessage: {}", self.log_context, broadcast.content);
+
+                self.broadcast(broadcast); // Relayed to everyone else, the sender gets no reply
+            }
+            //in case of a message with no request in it
+            None => {
+                self.empty_messages += 1;
+                warn!("[{}] Client sent an empty ClientMessage ({} so far).", self.log_context, self.empty_messages);
+
+                self.send_response(server_message::Message::ErrorResponse(ErrorResponse {
+                    code: ErrorCode::EmptyMessage.into(),
+                    message: "ClientMessage contained no request".to_string(),
+                }))?;
+                if self.empty_messages >= MAX_EMPTY_MESSAGES {
+                    warn!("[{}] Closing client after {} empty messages.", self.log_context, self.empty_messages);
+                    return Ok(false); // Nothing useful is coming from this client
+                }
+            }
+            // everything else is up to the configured handler
+            _ => {
+                info!("[{}] Received {}", self.log_context, describe_request(&client_message.message));
+
+                let request_type = message_type(&client_message.message);
+                let responses = self.handler.handle_many(client_message);
+                if responses.is_empty() {
+                    // Every request gets an answer, so a client never waits on a handler that had nothing to say
+                    warn!("[{}] Handler gave no response to {}, sending error response", self.log_context, request_type);
+                    self.send_response(server_message::Message::ErrorResponse(ErrorResponse {
+                        code: ErrorCode::Unspecified.into(),
+                        message: format!("{} was not answered", request_type),
+                    }))?;
+                }
+                for response in responses {
+                    self.write_message(&response)?; // Send the handler's replies in order
+                }
+            }
+        }
+
+        Ok(keep_open)
+    }
+
+    // Reject a request for lack of a free slot
+    fn send_overloaded(&mut self, message: &str) -> io::Result<()> {
+        self.send_response(server_message::Message::ErrorResponse(ErrorResponse {
+            code: ErrorCode::Overloaded.into(),
+            message: message.to_string(),
+        }))
+    }
+
+    // Tell the client how many frames it has sent so far, every progress_interval frames
+    fn send_progress_if_due(&mut self) -> io::Result<()> {
+        match self.config.progress_interval {
+            Some(interval) if self.frames_received.is_multiple_of(interval) => {
+                let progress = ProgressMessage {
+                    frames_received: self.frames_received,
+                };
+                self.send_response(server_message::Message::ProgressMessage(progress))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Take the next complete length-prefixed frame out of the pending bytes, if one is buffered.
+    // Err with the declared payload length as soon as a header is over max_message_size, whether or not its payload is here.
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, usize> {
+        let Some(declared) = read_header(&self.pending) else {
+            return Ok(None); // Header not fully received yet
+        };
+        if declared > self.config.max_message_size {
+            return Err(declared);
+        }
+        let frame_len = FRAME_HEADER_LEN + declared;
+        if self.pending.len() < frame_len {
+            return Ok(None); // Payload not fully received yet
+        }
+
+        let payload = self.pending[FRAME_HEADER_LEN..frame_len].to_vec();
+        self.pending.drain(..frame_len); // Leftover bytes belong to the next frame
+        self.counters.buffered_bytes.store(self.pending.len(), Ordering::SeqCst);
+        Ok(Some(payload))
+    }
+
+    // Encode a single response and write it to the client as one frame
+    fn send_response(&mut self, message: server_message::Message) -> io::Result<()> {
+        self.write_message(&ServerMessage {
+            message: Some(message),
+            ..Default::default()
+        })
+    }
+
+    // Write an already built ServerMessage to the client as one frame
+    fn write_message(&mut self, message: &ServerMessage) -> io::Result<()> {
+        let mut payload = match &self.timing {
+            Some(timing) => {
+                let handler_ns = timing.handler_started.elapsed().as_nanos() as u64;
+                let encode_started = Instant::now();
+                let mut payload = message.encode_to_vec();
+                let breakdown = TimingBreakdown {
+                    decode_ns: timing.decode_ns,
+                    handler_ns,
+                    encode_ns: encode_started.elapsed().as_nanos() as u64,
+                };
+
+                // Protobuf merges concatenated messages, so the breakdown can be appended to the encoded response
+                ServerMessage {
+                    timing: Some(breakdown),
+                    ..Default::default()
+                }
+                .encode(&mut payload)
+                .expect("Vec grows as needed");
+                payload
+            }
+            None => message.encode_to_vec(),
+        };
+        if self.request_id != 0 {
+            // Appended the same way, so handler replies don't need to know about correlation
+            ServerMessage {
+                request_id: self.request_id,
+                ..Default::default()
+            }
+            .encode(&mut payload)
+            .expect("Vec grows as needed");
+        }
+        self.write_payload(&payload)
+    }
+
+    // Frame an already encoded ServerMessage and send it
+    fn write_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+        let frame = encode_frame(payload);
+
+        self.stream.write_all(&frame)?; // Send the response
+        self.response_bytes += frame.len();
+        self.counters.add_bytes_out(frame.len()); // Only once it is written, so failed writes never count as sent
+        self.stream.flush() // Ensure the response is sent immediately
+    }
+}
+
+// Payload length declared by the big-endian u32 header at the start of bytes, None until all of the header is there
+fn read_header(bytes: &[u8]) -> Option<usize> {
+    let header = bytes.get(..FRAME_HEADER_LEN)?;
+    Some(u32::from_be_bytes(header.try_into().unwrap()) as usize)
+}
+
+// An encoded message preceded by its length, ready to write as one frame
+fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()); // Length prefix
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Declares REQUEST_TYPES and message_type from one list of ClientMessage variants, so the two can't drift apart.
+// message_type matches exhaustively, so a new variant doesn't compile until it is listed here.
+macro_rules! request_types {
+    ($($variant:ident),* $(,)?) => {
+        // Every name message_type can return, so operation limits can be checked for typos
+        const REQUEST_TYPES: &[&str] = &[$(stringify!($variant),)* "Empty"];
+
+        // Name of the request type, used in the access log and for operation limits
+        fn message_type(message: &Option<client_message::Message>) -> &'static str {
+            match message {
+                $(Some(client_message::Message::$variant(_)) => stringify!($variant),)*
+                None => "Empty",
+            }
+        }
+    };
+}
+
+request_types!(
+    EchoMessage,
+    AddRequest,
+    StreamEchoRequest,
+    StatsCalcRequest,
+    SubtractRequest,
+    PingRequest,
+    BroadcastMessage,
+    ReverseBytesRequest,
+    StatsRequest,
+    ServerInfoRequest,
+    MultiplyRequest,
+    DivideRequest,
+    RangeRequest,
+    QuitRequest,
+    SetRequest,
+    GetRequest,
+);
+
+// A request handed to the handler, as it is logged on arrival
+fn describe_request(message: &Option<client_message::Message>) -> String {
+    match message {
+        Some(client_message::Message::EchoMessage(echo_message)) => format!("EchoMessage: {}", echo_message.content),
+        Some(client_message::Message::AddRequest(add_request)) => {
+            format!("AddRequest: {} + {}", add_request.a, add_request.b)
+        }
+        Some(client_message::Message::SubtractRequest(subtract_request)) => {
+            format!("SubtractRequest: {} - {}", subtract_request.a, subtract_request.b)
+        }
+        Some(client_message::Message::MultiplyRequest(multiply_request)) => {
+            format!("MultiplyRequest: {} * {}", multiply_request.a, multiply_request.b)
+        }
+        Some(client_message::Message::DivideRequest(divide_request)) => {
+            format!("DivideRequest: {} / {}", divide_request.a, divide_request.b)
+        }
+        Some(client_message::Message::StatsCalcRequest(stats_request)) => {
+            format!("StatsCalcRequest with {} values", stats_request.values.len())
+        }
+        Some(client_message::Message::ReverseBytesRequest(reverse_request)) => {
+            format!("ReverseBytesRequest with {} bytes", reverse_request.data.len())
+        }
+        Some(client_message::Message::RangeRequest(range_request)) => {
+            format!("RangeRequest: {}..={}", range_request.start, range_request.end)
+        }
+        other => message_type(other).to_string(), // Answered by the server itself, which logs them in more detail
+    }
+}
+
+// Whether the peer already closed or reset the connection before it was accepted.
+// A successful empty peek means end of stream with nothing sent, so there is nothing to serve.
+// Unix sockets can't be peeked on stable Rust, so they are always taken to be alive.
+fn is_dead_on_arrival(stream: &Stream) -> bool {
+    let stream = match stream {
+        Stream::Tcp(stream) => stream,
+        #[cfg(unix)]
+        Stream::Unix(_) => return false,
+    };
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let dead = match stream.peek(&mut [0; 1]) {
+        Ok(0) => true, // Peer sent FIN without any data
+        Ok(_) => false, // A request is already waiting
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => false, // Open and idle
+        Err(_) => true, // Reset or otherwise broken
+    };
+    dead || stream.set_nonblocking(false).is_err()
+}
+
+// Whether decoding failed on a string field holding invalid UTF-8. prost only says so in the error text.
+fn is_invalid_utf8(error: &prost::DecodeError) -> bool {
+    error.to_string().contains("not UTF-8")
+}
+
+// Whether an I/O error means the peer closed or reset the connection, e.g. a write after it stopped reading
+fn is_peer_gone(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+    )
+}
+
+// Whether an I/O error is a blocking write giving up after the stream's write timeout
+fn is_write_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// Whether an accept error only concerns the one connection, which the peer gave up on before it was accepted
+fn is_transient_accept_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+// Whether accept failed for lack of file descriptors, buffers or memory, which only time can fix
+fn is_resource_exhausted(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = error.raw_os_error() {
+        return matches!(code, libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM);
+    }
+    error.kind() == ErrorKind::OutOfMemory
+}
+
+// Bind a TCP listener to the first of addr's resolved addresses that works, as TcpListener::bind does
+#[cfg(unix)]
+fn bind_tcp(addr: &str, config: &ServerConfig) -> io::Result<TcpListener> {
+    use std::net::ToSocketAddrs;
+
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_socket(addr, config.reuse_addr) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Address resolved to nothing")))
+}
+
+#[cfg(not(unix))]
+fn bind_tcp(addr: &str, _config: &ServerConfig) -> io::Result<TcpListener> {
+    TcpListener::bind(addr)
+}
+
+// What TcpListener::bind does on Unix, except that SO_REUSEADDR is set as asked rather than always on
+#[cfg(unix)]
+fn bind_socket(addr: SocketAddr, reuse_addr: bool) -> io::Result<TcpListener> {
+    use std::{
+        mem,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+
+    let check = |result: libc::c_int| if result < 0 { Err(io::Error::last_os_error()) } else { Ok(result) };
+    let family = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    // SAFETY: a plain syscall, and the descriptor it returns is owned straight away so every error path closes it
+    let socket = unsafe { OwnedFd::from_raw_fd(check(libc::socket(family, libc::SOCK_STREAM, 0))?) };
+    let fd = socket.as_raw_fd();
+
+    // SAFETY: fd is a valid socket for all of the calls below, and each pointer covers the size passed with it
+    unsafe {
+        check(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+        let reuse = libc::c_int::from(reuse_addr);
+        check(libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            (&reuse as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ))?;
+        match addr {
+            SocketAddr::V4(addr) => {
+                let mut raw: libc::sockaddr_in = mem::zeroed();
+                raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                raw.sin_port = addr.port().to_be();
+                raw.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                let len = mem::size_of_val(&raw) as libc::socklen_t;
+                check(libc::bind(fd, (&raw as *const libc::sockaddr_in).cast(), len))?;
+            }
+            SocketAddr::V6(addr) => {
+                let mut raw: libc::sockaddr_in6 = mem::zeroed();
+                raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = addr.port().to_be();
+                raw.sin6_addr.s6_addr = addr.ip().octets();
+                raw.sin6_flowinfo = addr.flowinfo();
+                raw.sin6_scope_id = addr.scope_id();
+                let len = mem::size_of_val(&raw) as libc::socklen_t;
+                check(libc::bind(fd, (&raw as *const libc::sockaddr_in6).cast(), len))?;
+            }
+        }
+        check(libc::listen(fd, 128))?; // The backlog std uses
+    }
+    Ok(TcpListener::from(socket))
+}
+
+// Text of a caught panic, for the common &str and String payloads
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+// A client or worker thread, with a channel that disconnects once it has finished, so waiting for it can time out
+struct TrackedThread {
+    handle: JoinHandle<()>,
+    done: mpsc::Receiver<()>, // Never sent on; disconnects when the thread's closure returns or unwinds
+}
+
+impl TrackedThread {
+    fn spawn(f: impl FnOnce() + Send + 'static) -> Self {
+        let (finished, done) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let _finished = finished; // Dropped last, after f, however f exits
+            f();
+        });
+        TrackedThread { handle, done }
+    }
+}
+
+// Called with a client's address when it connects or disconnects
+type ConnectionCallback = Arc<dyn Fn(SocketAddr) + Send + Sync>;
+
+// An accepted connection waiting for a pool worker: its id, stream and peer address
+type QueuedClient = (ConnId, Stream, SocketAddr);
+
+// Traffic summed over every connection the server has had, read by Server::stats
+#[derive(Default)]
+struct TrafficTotals {
+    messages: AtomicU64, // Messages decoded
+    bytes_in: AtomicU64, // Bytes read from client sockets
+    bytes_out: AtomicU64, // Bytes written to client sockets
+}
+
+// Per-connection counters, updated by the client thread and read by Server::connection_metrics_snapshot
+struct ConnectionCounters {
+    connected_at: Instant, // When the connection was registered
+    messages: AtomicU64, // Messages decoded
+    bytes_in: AtomicU64, // Bytes read from the socket
+    bytes_out: AtomicU64, // Bytes written to the socket, responses and broadcasts alike
+    buffered_bytes: AtomicUsize, // Bytes received but not yet processed
+    last_activity_ms: AtomicU64, // When the last message was answered, in milliseconds after connected_at
+    totals: Arc<TrafficTotals>, // Server-wide totals, kept in step with this connection's counts
+}
+
+impl ConnectionCounters {
+    fn new(totals: Arc<TrafficTotals>) -> Self {
+        ConnectionCounters {
+            connected_at: Instant::now(),
+            messages: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            buffered_bytes: AtomicUsize::new(0),
+            last_activity_ms: AtomicU64::new(0),
+            totals,
+        }
+    }
+
+    fn add_message(&self) {
+        self.messages.fetch_add(1, Ordering::SeqCst);
+        self.totals.messages.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn add_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::SeqCst);
+        self.totals.bytes_in.fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    fn add_bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::SeqCst);
+        self.totals.bytes_out.fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    // Note that a message was just answered
+    fn touch(&self) {
+        self.last_activity_ms
+            .store(self.connected_at.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+
+    // Time since the last message was answered, or since connecting if none was
+    fn idle(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.last_activity_ms.load(Ordering::SeqCst));
+        self.connected_at.elapsed().saturating_sub(last_activity)
+    }
+}
 
-                        self.stream.write_all(&payload)?; // Send the addition result
-                        self.stream.flush()?; // Ensure the response is sent
+// One connection's line in Server::connection_metrics_snapshot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionMetrics {
+    pub id: ConnId, // Server-assigned connection id
+    pub peer_addr: SocketAddr, // Address the connection came from
+    pub messages: u64, // Messages received and decoded
+    pub bytes_in: u64, // Bytes received, including frame headers
+    pub bytes_out: u64, // Bytes sent, including frame headers
+    pub queue_depth: usize, // Bytes received but not yet processed
+    pub idle: Duration, // Time since the last message was answered, or since connecting if none was
+    pub connected_for: Duration, // Time since the connection was accepted
+    pub nodelay: bool, // Whether TCP_NODELAY is set on the server's end of the connection
+}
+
+// What the server keeps about each live connection
+struct ClientEntry {
+    stream: Stream, // Clone of the client's stream, so stop can interrupt blocked reads
+    peer_addr: SocketAddr, // Address the connection came from
+    quiesce: Arc<AtomicBool>, // Shared with the client thread, set to recycle the connection
+    cancelled: Arc<AtomicBool>, // Shared with the client thread, set when stop force-closes the connection
+    outbox: mpsc::Sender<Arc<[u8]>>, // Feeds the client's inbox with encoded broadcasts from other clients
+    counters: Arc<ConnectionCounters>, // Shared with the client thread, which keeps them up to date
+}
+
+// The client thread's side of its registry entry
+struct Registration {
+    quiesce: Arc<AtomicBool>, // Set when the server wants the connection recycled
+    cancelled: Arc<AtomicBool>, // Set when the server gives up waiting for the connection to drain
+    inbox: mpsc::Receiver<Arc<[u8]>>, // Encoded broadcasts waiting to be written to the client
+    counters: Arc<ConnectionCounters>, // Traffic counters the server reads
+}
+
+// Everything a client thread needs from the server
+#[derive(Clone)]
+struct ClientContext {
+    config: ServerConfig, // Settings handed to each client
+    is_running: Arc<AtomicBool>, // Server running flag
+    request_slots: Option<Arc<Semaphore>>, // Server-wide in-flight request limit, if configured
+    operation_slots: Arc<HashMap<&'static str, Semaphore>>, // Per request type in-flight limits
+    clients: Arc<Mutex<HashMap<ConnId, ClientEntry>>>, // Registry the connection is removed from once finished
+    active_clients: Arc<AtomicUsize>, // Connected client counter
+    peak_read_ahead: Arc<AtomicUsize>, // High-water mark of bytes buffered by a single client
+    handler: Arc<dyn MessageHandler + Send + Sync>, // Shared by every client
+    broadcasts_encoded: Arc<AtomicU64>, // Count of broadcast payloads encoded
+    totals: Arc<TrafficTotals>, // Server-wide traffic, added to by every connection's counters
+    store: Arc<Mutex<HashMap<String, String>>>, // Shared key-value store
+    on_disconnect: Option<ConnectionCallback>, // Run once the connection is finished
+    started_at: Instant, // When the server was created
+}
+
+impl ClientContext {
+    // Track the connection so stop can shut it down, client_count includes it and broadcasts reach it.
+    // Returns the half of the entry the client thread keeps.
+    fn register(&self, client_id: ConnId, stream: &Stream, peer_addr: SocketAddr) -> io::Result<Registration> {
+        let quiesce = Arc::new(AtomicBool::new(false));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (outbox, inbox) = mpsc::channel();
+        let counters = Arc::new(ConnectionCounters::new(Arc::clone(&self.totals)));
+        let entry = ClientEntry {
+            stream: stream.try_clone()?, // Keep a handle so stop can shut the stream down
+            peer_addr,
+            quiesce: Arc::clone(&quiesce),
+            cancelled: Arc::clone(&cancelled),
+            outbox,
+            counters: Arc::clone(&counters),
+        };
+        self.clients.lock().unwrap().insert(client_id, entry);
+        self.active_clients.fetch_add(1, Ordering::SeqCst);
+        Ok(Registration {
+            quiesce,
+            cancelled,
+            inbox,
+            counters,
+        })
+    }
+
+    // Service a registered connection until it ends, then forget it
+    fn serve(&self, stream: Stream, client_id: ConnId, peer_addr: SocketAddr, registration: Registration) {
+        match Client::new(stream, client_id, peer_addr, self, registration) {
+            Ok(mut client) => {
+                // handle returns once the client disconnects, asks to close or the server stops.
+                // A panic, e.g. in a custom handler, ends only this connection and leaves the thread or worker usable.
+                match panic::catch_unwind(AssertUnwindSafe(|| client.handle())) {
+                    Err(payload) => {
+                        error!("[{}] Client thread panicked: {}", client.log_context, panic_message(&*payload));
                     }
-                    None => {
-                        error!("Received a ClientMessage with no message!");
+                    Ok(Ok(())) => {}
+                    Ok(Err(ref e)) if is_write_timeout(e) => {
+                        // Reads time out inside handle, so this was a response the client isn't reading
+                        warn!(
+                            "[{}] Client stopped reading, write timed out after {:?}; closing connection.",
+                            client.log_context, self.config.write_timeout
+                        );
                     }
-                },
-                Err(e) => {
-                    error!("Failed to decode ClientMessage: {}", e); // Log decoding errors
+                    Ok(Err(ref e)) if is_peer_gone(e) => {
+                        // The client hung up without reading what it asked for, which is its choice, not a fault
+                        info!(
+                            "[{}] Client closed the connection before its response was written: {}",
+                            client.log_context, e
+                        );
+                    }
+                    Ok(Err(e)) => error!("[{}] Error handling client: {}", client.log_context, e), // Log client errors
+                }
+
+                if let Err(e) = client.stream.shutdown(Shutdown::Both) {
+                    debug!("[{}] Failed to shutdown stream: {}", client.log_context, e); // Already closed by the peer or by stop
                 }
             }
+            Err(e) => {
+                error!("[conn={} peer={}] Failed to configure client stream: {}", client_id, peer_addr, e);
+            }
+        }
+
+        self.clients.lock().unwrap().remove(&client_id); // Connection is finished
+        self.active_clients.fetch_sub(1, Ordering::SeqCst);
+        if let Some(on_disconnect) = &self.on_disconnect {
+            on_disconnect(peer_addr);
         }
     }
 }
 
+// Collects settings for a server, for when more than one differs from Server::new.
+#[derive(Default)]
+pub struct ServerBuilder {
+    addrs: Vec<String>, // Where to listen, at least one is required
+    config: ServerConfig, // Everything else handed to the server
+    handler: Option<Arc<dyn MessageHandler + Send + Sync>>, // DefaultHandler unless set
+    operation_limits: Vec<(&'static str, usize)>, // Per request type in-flight limits
+    max_queued_connections: Option<usize>, // Worker queue cap, None for no cap
+    allowlist: Option<Vec<IpAddr>>, // Peer addresses allowed to connect, None to allow anyone
+}
+
+impl ServerBuilder {
+    // Listen on addr, in addition to any address already given
+    pub fn addr(mut self, addr: &str) -> Self {
+        self.addrs.push(addr.to_string());
+        self
+    }
+
+    // Size of each client's read buffer
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.config.buffer_size = size;
+        self
+    }
+
+    // How long client reads block at most before checking for shutdown
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    // At most max requests are processed at once across all clients.
+    // A request waits up to wait_timeout for a free slot and is otherwise answered with OVERLOADED.
+    pub fn max_concurrent_requests(mut self, max: usize, wait_timeout: Duration) -> Self {
+        self.config.max_concurrent_requests = Some(max);
+        self.config.request_wait_timeout = wait_timeout;
+        self
+    }
+
+    // Send every client a ProgressMessage after each interval frames it sends
+    pub fn progress_interval(mut self, interval: u64) -> Self {
+        self.config.progress_interval = Some(interval);
+        self
+    }
+
+    // Log one line per request to the "access" log target, so the lines can be routed separately
+    pub fn access_log(mut self, enabled: bool) -> Self {
+        self.config.access_log = enabled;
+        self
+    }
+
+    // Serve connections from a fixed pool of worker threads instead of a thread each, see Server::with_workers
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = Some(workers);
+        self
+    }
+
+    // Each client buffers at most limit unprocessed bytes, or one whole frame if that is larger.
+    // Reading pauses at the limit until buffered frames are handled, leaving the rest to TCP flow control.
+    pub fn read_ahead_limit(mut self, limit: usize) -> Self {
+        self.config.read_ahead_limit = Some(limit);
+        self
+    }
+
+    // Disconnect a client that sends no complete message for timeout, see Server::with_idle_timeout
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    // A client must finish each frame within timeout of sending its first byte, or it is disconnected.
+    // Unlike the idle timeout this only runs while a frame is partly received, so long pauses between requests are fine.
+    pub fn frame_timeout(mut self, timeout: Duration) -> Self {
+        self.config.frame_timeout = Some(timeout);
+        self
+    }
+
+    // Ping the server through a TCP listener in run before serving anyone, and return an error instead of serving if
+    // no pong comes back. The probe is told apart from real clients by its address, which Unix peers don't have,
+    // so a server with only Unix listeners skips it.
+    pub fn self_test(mut self, enabled: bool) -> Self {
+        self.config.self_test = enabled;
+        self
+    }
+
+    // After serving max connections in total, refuse new ones, wait for the connected clients to leave and then
+    // stop, so run returns without a call to stop
+    pub fn max_lifetime_connections(mut self, max: u64) -> Self {
+        self.config.max_lifetime_connections = Some(max);
+        self
+    }
+
+    // Disconnect a client whose frame header declares more than size payload bytes as soon as the header arrives.
+    // The frame is never decoded, even if all of it came in the same read. Up to 1 MB unless set.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.config.max_message_size = size;
+        self
+    }
+
+    // Most clients connected at once, counting those waiting for a pool worker.
+    // Connections beyond it are sent a SERVER_FULL error and closed as soon as they are accepted.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    // Total unprocessed bytes clients may hold before new connections are refused with SERVER_FULL
+    // and the client buffering the most is closed for each one refused
+    pub fn max_buffered_bytes(mut self, max: usize) -> Self {
+        self.config.max_buffered_bytes = Some(max);
+        self
+    }
+
+    // Process at most msgs_per_sec messages per second from each connection, see Server::with_rate_limit
+    pub fn rate_limit(mut self, msgs_per_sec: u32) -> Self {
+        self.config.rate_limit = Some(msgs_per_sec);
+        self
+    }
+
+    // Cap on connections waiting for a pool worker, see Server::set_max_queued_connections
+    pub fn max_queued_connections(mut self, max: usize) -> Self {
+        self.max_queued_connections = Some(max);
+        self
+    }
+
+    // What to do with connections accepted while the worker queue is full, Reject unless set
+    pub fn saturation_policy(mut self, policy: SaturationPolicy) -> Self {
+        self.config.saturation_policy = policy;
+        self
+    }
+
+    // TCP_NODELAY on client sockets, on unless set, trading a few extra small packets for lower reply latency
+    pub fn nodelay(mut self, enabled: bool) -> Self {
+        self.config.nodelay = enabled;
+        self
+    }
+
+    // SO_REUSEADDR on TCP listeners, on unless set, so a server restarted straight after stop can bind a port
+    // its old connections still hold in TIME_WAIT. Off makes such a bind fail with AddrInUse instead.
+    // Unix only: elsewhere listeners are bound the way std binds them.
+    #[cfg(unix)]
+    pub fn reuse_addr(mut self, enabled: bool) -> Self {
+        self.config.reuse_addr = enabled;
+        self
+    }
+
+    // How long writing a response may block before the client is taken to be too slow and closed
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    // How often the server re-checks while it polls, e.g. for clients to leave when draining.
+    // Lower trades idle CPU for latency; reads block with read_timeout instead and aren't affected.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.config.poll_interval = interval;
+        self
+    }
+
+    pub fn join_timeout(mut self, timeout: Duration) -> Self {
+        self.config.join_timeout = timeout;
+        self
+    }
+
+    // Every accepted connection waits for delay before it is served, and the listener accepts nothing else
+    // meanwhile. Only for exercising client timeouts in tests.
+    #[cfg(feature = "accept-delay")]
+    pub fn accept_delay(mut self, delay: Duration) -> Self {
+        self.config.accept_delay = delay;
+        self
+    }
+
+    // Only serve TCP peers whose IP is one of allowed, see Server::with_allowlist
+    pub fn allowlist(mut self, allowed: Vec<IpAddr>) -> Self {
+        self.allowlist = Some(allowed);
+        self
+    }
+
+    // Answer requests with handler instead of DefaultHandler
+    pub fn handler(mut self, handler: Box<dyn MessageHandler + Send + Sync>) -> Self {
+        self.handler = Some(Arc::from(handler));
+        self
+    }
+
+    // Let at most max requests of one type, named as in the access log (e.g. "StatsCalcRequest"), be in flight at once
+    // across all clients. Others wait up to the request wait timeout like they do for the server-wide limit, then get
+    // OVERLOADED. Types it isn't called for are not limited. Can be called once per type.
+    pub fn operation_limit(mut self, operation: &'static str, max: usize) -> Self {
+        self.operation_limits.push((operation, max));
+        self
+    }
+
+    // Check the settings and bind every address
+    pub fn build(self) -> Result<Server, ServerError> {
+        if self.operation_limits.iter().any(|&(_, max)| max == 0) {
+            return Err(ServerError::InvalidConfig("Operation limits must be greater than zero"));
+        }
+        if self.operation_limits.iter().any(|(operation, _)| !REQUEST_TYPES.contains(operation)) {
+            return Err(ServerError::InvalidConfig("Operation limits must name a known request type"));
+        }
+
+        let addrs: Vec<&str> = self.addrs.iter().map(String::as_str).collect();
+        let mut server = Server::with_config(&addrs, self.config)?;
+        if let Some(handler) = self.handler {
+            server.handler = handler;
+        }
+        server.allowlist = self.allowlist;
+        server.set_max_queued_connections(self.max_queued_connections);
+        server.operation_slots = Arc::new(
+            self.operation_limits
+                .into_iter()
+                .map(|(operation, max)| (operation, Semaphore::new(max)))
+                .collect(),
+        );
+        Ok(server)
+    }
+}
+
+// Point-in-time counters returned by Server::stats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    pub connected_clients: usize, // Clients currently being served
+    pub queued_connections: usize, // Accepted connections waiting for a pool worker
+    pub queue_high_water: usize, // Most connections ever waiting for a pool worker at once
+    pub rejected_connections: u64, // Connections closed because the worker queue was full or max_connections were connected
+    pub dead_on_accept: u64, // Connections the peer had already closed or reset by the time they were accepted
+    pub burst_clients: usize, // Clients served on extra threads because the worker pool was saturated
+    pub broadcasts_encoded: u64, // Broadcast payloads encoded, once per broadcast however many clients receive it
+    pub connections_accepted: u64, // Connections accepted since the server was created, including ones turned away
+    pub messages_processed: u64, // ClientMessages decoded, over every connection so far
+    pub bytes_read: u64, // Bytes received from clients, including frame headers
+    pub bytes_written: u64, // Bytes sent to clients, including frame headers
+}
+
+// Logs through the log facade. Which levels are kept is up to the logger the application installs, e.g. with
+// log::set_max_level or env_logger's RUST_LOG; the server never changes that filter itself.
 pub struct Server {
-    listener: TcpListener, // Listener for incoming connections
+    listeners: Vec<Listener>, // Listeners for incoming connections, each accepted on its own thread
     is_running: Arc<AtomicBool>, // Shared flag to control server status
-    client_threads: Arc<Mutex<Vec<thread::JoinHandle<()>>>>, // Threads handling clients
+    ready: AtomicBool, // Set once run is accepting connections, after the self-test if there is one
+    client_threads: Arc<Mutex<Vec<TrackedThread>>>, // Threads handling clients
+    clients: Arc<Mutex<HashMap<ConnId, ClientEntry>>>, // Every live connection, so stop can interrupt blocked reads
+    next_client_id: AtomicU64, // Id given to the next accepted client
+    active_clients: Arc<AtomicUsize>, // Number of clients currently connected
+    peak_read_ahead: Arc<AtomicUsize>, // Most unprocessed bytes any one client has had buffered
+    config: ServerConfig, // Settings handed to each client
+    request_slots: Option<Arc<Semaphore>>, // Server-wide in-flight request limit, if configured
+    operation_slots: Arc<HashMap<&'static str, Semaphore>>, // In-flight limits by request type name, empty unless configured
+    handler: Arc<dyn MessageHandler + Send + Sync>, // Answers requests, DefaultHandler unless replaced
+    queued_connections: Arc<AtomicUsize>, // Accepted connections no worker has picked up yet
+    queue_high_water: AtomicUsize, // Most connections queued at once
+    max_queued_connections: AtomicUsize, // Queue cap, usize::MAX for no cap; changeable while running
+    rejected_connections: AtomicU64, // Connections turned away by the queue cap or the connection cap
+    dead_on_accept: AtomicU64, // Accepted connections that were already gone
+    burst_clients: Arc<AtomicUsize>, // Clients on extra threads under SaturationPolicy::Burst
+    lifetime_connections: AtomicU64, // Connections served since the server started, checked against the lifetime limit
+    broadcasts_encoded: Arc<AtomicU64>, // Broadcast payloads encoded by client threads
+    connections_accepted: AtomicU64, // Connections accepted, whether or not they were then served
+    totals: Arc<TrafficTotals>, // Messages and bytes over every connection so far
+    store: Arc<Mutex<HashMap<String, String>>>, // Values set by SetRequest, read by GetRequest from any client
+    on_connect: Option<ConnectionCallback>, // Run for every connection about to be served
+    on_disconnect: Option<ConnectionCallback>, // Run when a served connection ends
+    allowlist: Option<Vec<IpAddr>>, // Peer addresses allowed to connect, None to allow anyone
+    started_at: Instant, // When the server was created, uptime in ServerInfoResponse counts from here
 }
 
 impl Server {
-    pub fn new(addr: &str) -> io::Result<Self> {
-        let listener = TcpListener::bind(addr)?; // Bind the listener to the address
+    pub fn new(addr: &str) -> Result<Self, ServerError> {
+        Server::builder().addr(addr).build()
+    }
+
+    // Start configuring a server with several options at once, see ServerBuilder
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    // Same as new, but each client reads into a buffer of the given size
+    pub fn with_buffer_size(addr: &str, size: usize) -> Result<Self, ServerError> {
+        Server::builder().addr(addr).buffer_size(size).build()
+    }
+
+    // Same as new, but connections are serviced by a fixed pool of worker threads instead of a thread each.
+    // Accepted connections queue until a worker is free, and a worker serves one connection at a time.
+    pub fn with_workers(addr: &str, workers: usize) -> Result<Self, ServerError> {
+        Server::builder().addr(addr).workers(workers).build()
+    }
+
+    // Same as new, but a client that sends no complete message for timeout is disconnected.
+    // Idleness is checked whenever a read returns or times out, so it is detected within read_timeout.
+    pub fn with_idle_timeout(addr: &str, timeout: Duration) -> Result<Self, ServerError> {
+        Server::builder().addr(addr).idle_timeout(timeout).build()
+    }
+
+    // Same as new, but requests are answered by the given handler instead of DefaultHandler
+    pub fn with_handler(addr: &str, handler: Box<dyn MessageHandler + Send + Sync>) -> Result<Self, ServerError> {
+        Server::builder().addr(addr).handler(handler).build()
+    }
+
+    // Same as new, but connections from any IP not in allowed are closed as soon as they are accepted.
+    // IPv4 peers reaching an IPv6 listener are matched by their IPv4 address. Unix socket clients are never filtered.
+    pub fn with_allowlist(addr: &str, allowed: Vec<IpAddr>) -> Result<Self, ServerError> {
+        Server::builder().addr(addr).allowlist(allowed).build()
+    }
+
+    // Same as new, but each connection has at most msgs_per_sec messages processed per second.
+    // Up to a second's worth may come in a burst; beyond that the client's messages are delayed, not dropped.
+    pub fn with_rate_limit(addr: &str, msgs_per_sec: u32) -> Result<Self, ServerError> {
+        Server::builder().addr(addr).rate_limit(msgs_per_sec).build()
+    }
+
+    // Same as new, but listening on every one of addrs, e.g. an IPv4 and an IPv6 address for dual-stack
+    pub fn new_multi(addrs: &[&str]) -> Result<Self, ServerError> {
+        addrs.iter().fold(Server::builder(), |builder, addr| builder.addr(addr)).build()
+    }
+
+    // Same as new, but listening on a Unix domain socket at path instead of a TCP address.
+    // The socket file must not exist yet, and is removed again when the server is dropped.
+    // Unix clients have no peer address, so callbacks and logs see them all as 0.0.0.0:0.
+    #[cfg(unix)]
+    pub fn new_unix(path: &str) -> Result<Self, ServerError> {
+        let config = ServerConfig::default();
+        Server::check_config(&config)?;
+        let listener = UnixListener::bind(path).map_err(ServerError::Bind)?;
+        Ok(Server::with_listeners(vec![Listener::Unix(listener, PathBuf::from(path))], config))
+    }
+
+    fn with_config(addrs: &[&str], config: ServerConfig) -> Result<Self, ServerError> {
+        if addrs.is_empty() {
+            return Err(ServerError::InvalidConfig("At least one address is required"));
+        }
+        Server::check_config(&config)?;
+        let listeners = addrs
+            .iter()
+            .map(|addr| bind_tcp(addr, &config).map(Listener::Tcp).map_err(ServerError::Bind)) // Bind a listener to each address
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Server::with_listeners(listeners, config))
+    }
+
+    fn check_config(config: &ServerConfig) -> Result<(), ServerError> {
+        if config.buffer_size == 0 {
+            return Err(ServerError::InvalidConfig("Buffer size must be greater than zero"));
+        }
+        if config.max_message_size == 0 {
+            return Err(ServerError::InvalidConfig("Maximum message size must be greater than zero"));
+        }
+        if config.read_timeout.is_zero() {
+            return Err(ServerError::InvalidConfig("Read timeout must be greater than zero"));
+        }
+        if config.write_timeout.is_zero() {
+            return Err(ServerError::InvalidConfig("Write timeout must be greater than zero"));
+        }
+        if config.idle_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(ServerError::InvalidConfig("Idle timeout must be greater than zero"));
+        }
+        if config.frame_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(ServerError::InvalidConfig("Frame timeout must be greater than zero"));
+        }
+        if config.progress_interval == Some(0) {
+            return Err(ServerError::InvalidConfig("Progress interval must be greater than zero"));
+        }
+        if config.max_concurrent_requests == Some(0) {
+            return Err(ServerError::InvalidConfig("Maximum concurrent requests must be greater than zero"));
+        }
+        if config.read_ahead_limit == Some(0) {
+            return Err(ServerError::InvalidConfig("Read-ahead limit must be greater than zero"));
+        }
+        if config.max_lifetime_connections == Some(0) {
+            return Err(ServerError::InvalidConfig(
+                "Maximum lifetime connections must be greater than zero",
+            ));
+        }
+        if config.workers == Some(0) {
+            return Err(ServerError::InvalidConfig("Worker count must be greater than zero"));
+        }
+        if config.saturation_policy == SaturationPolicy::Burst(0) {
+            return Err(ServerError::InvalidConfig("Burst capacity must be greater than zero"));
+        }
+        if config.max_buffered_bytes == Some(0) {
+            return Err(ServerError::InvalidConfig("Maximum buffered bytes must be greater than zero"));
+        }
+        if config.rate_limit == Some(0) {
+            return Err(ServerError::InvalidConfig("Rate limit must be greater than zero"));
+        }
+        if config.poll_interval.is_zero() {
+            return Err(ServerError::InvalidConfig("Poll interval must be greater than zero"));
+        }
+        if config.join_timeout.is_zero() {
+            return Err(ServerError::InvalidConfig("Join timeout must be greater than zero"));
+        }
+        if config.max_connections == Some(0) {
+            return Err(ServerError::InvalidConfig("Maximum connections must be greater than zero"));
+        }
+        Ok(())
+    }
+
+    fn with_listeners(listeners: Vec<Listener>, config: ServerConfig) -> Self {
         let is_running = Arc::new(AtomicBool::new(false)); // Initialize running state
         let client_threads = Arc::new(Mutex::new(Vec::new())); // Initialize thread storage
+        let request_slots = config
+            .max_concurrent_requests
+            .map(|max| Arc::new(Semaphore::new(max))); // One permit per in-flight request
 
-        Ok(Server {
-            listener,
+        Server {
+            listeners,
             is_running,
+            ready: AtomicBool::new(false),
             client_threads,
-        })
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            next_client_id: AtomicU64::new(0),
+            active_clients: Arc::new(AtomicUsize::new(0)),
+            peak_read_ahead: Arc::new(AtomicUsize::new(0)),
+            config,
+            request_slots,
+            operation_slots: Arc::new(HashMap::new()),
+            handler: Arc::new(DefaultHandler),
+            queued_connections: Arc::new(AtomicUsize::new(0)),
+            queue_high_water: AtomicUsize::new(0),
+            max_queued_connections: AtomicUsize::new(usize::MAX),
+            rejected_connections: AtomicU64::new(0),
+            dead_on_accept: AtomicU64::new(0),
+            burst_clients: Arc::new(AtomicUsize::new(0)),
+            lifetime_connections: AtomicU64::new(0),
+            broadcasts_encoded: Arc::new(AtomicU64::new(0)),
+            connections_accepted: AtomicU64::new(0),
+            totals: Arc::new(TrafficTotals::default()),
+            store: Arc::new(Mutex::new(HashMap::new())),
+            on_connect: None,
+            on_disconnect: None,
+            allowlist: None,
+            started_at: Instant::now(),
+        }
+    }
+
+    // Address the first listener is actually bound to, e.g. to find the port the OS picked for port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    // Addresses of every listener, in the order they were given
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(Listener::local_addr).collect()
+    }
+
+    // Run callback with the peer address of every connection the server is about to serve, on the accepting thread.
+    // Set it before calling run; a slow callback holds up accepting.
+    pub fn on_connect(&mut self, callback: impl Fn(SocketAddr) + Send + Sync + 'static) {
+        self.on_connect = Some(Arc::new(callback));
+    }
+
+    // Run callback with the peer address of every served connection once it has ended, on the client's thread
+    pub fn on_disconnect(&mut self, callback: impl Fn(SocketAddr) + Send + Sync + 'static) {
+        self.on_disconnect = Some(Arc::new(callback));
+    }
+
+    // Whether run has started and stop hasn't been called since. The listeners are bound from construction,
+    // so once this is true connections are accepted.
+    pub fn is_running(&self) -> bool {
+        self.is_running.load(Ordering::SeqCst)
+    }
+
+    // Block until run is accepting connections, for at most timeout. Returns whether it got there in time.
+    // A server whose startup self-test fails never becomes ready.
+    pub fn wait_until_ready(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.ready.load(Ordering::SeqCst) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    // Number of clients currently connected
+    pub fn client_count(&self) -> usize {
+        self.active_clients.load(Ordering::SeqCst)
+    }
+
+    // Metrics for every live connection, ordered by id. Taken under the registry lock, so the set of
+    // connections is consistent, though each connection's counters may move on while it is read.
+    pub fn connection_metrics_snapshot(&self) -> Vec<ConnectionMetrics> {
+        let clients = self.clients.lock().unwrap();
+        let mut snapshot: Vec<ConnectionMetrics> = clients
+            .iter()
+            .map(|(&id, client)| {
+                let counters = &client.counters;
+                ConnectionMetrics {
+                    id,
+                    peer_addr: client.peer_addr,
+                    messages: counters.messages.load(Ordering::SeqCst),
+                    bytes_in: counters.bytes_in.load(Ordering::SeqCst),
+                    bytes_out: counters.bytes_out.load(Ordering::SeqCst),
+                    queue_depth: counters.buffered_bytes.load(Ordering::SeqCst),
+                    idle: counters.idle(),
+                    connected_for: counters.connected_at.elapsed(),
+                    nodelay: client.stream.nodelay().unwrap_or(false),
+                }
+            })
+            .collect();
+        snapshot.sort_by_key(|metrics| metrics.id);
+        snapshot
+    }
+
+    // Id and peer address of every live connection, ordered by id
+    pub fn connections(&self) -> Vec<(ConnId, SocketAddr)> {
+        let mut connections: Vec<(ConnId, SocketAddr)> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, client)| (id, client.peer_addr))
+            .collect();
+        connections.sort_by_key(|&(id, _)| id);
+        connections
+    }
+
+    // Most unprocessed bytes any single client has had buffered since the server started
+    pub fn peak_read_ahead(&self) -> usize {
+        self.peak_read_ahead.load(Ordering::SeqCst)
+    }
+
+    // Current client and worker-queue counters
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            connected_clients: self.client_count(),
+            queued_connections: self.queued_connections.load(Ordering::SeqCst),
+            queue_high_water: self.queue_high_water.load(Ordering::SeqCst),
+            rejected_connections: self.rejected_connections.load(Ordering::SeqCst),
+            dead_on_accept: self.dead_on_accept.load(Ordering::SeqCst),
+            burst_clients: self.burst_clients.load(Ordering::SeqCst),
+            broadcasts_encoded: self.broadcasts_encoded.load(Ordering::SeqCst),
+            connections_accepted: self.connections_accepted.load(Ordering::SeqCst),
+            messages_processed: self.totals.messages.load(Ordering::SeqCst),
+            bytes_read: self.totals.bytes_in.load(Ordering::SeqCst),
+            bytes_written: self.totals.bytes_out.load(Ordering::SeqCst),
+        }
+    }
+
+    // Cap how many accepted connections may wait for a pool worker, None for no cap. Takes effect immediately.
+    // Connections accepted while the queue is full go to the saturation policy. Without a worker pool nothing queues.
+    pub fn set_max_queued_connections(&self, max: Option<usize>) {
+        self.max_queued_connections
+            .store(max.unwrap_or(usize::MAX), Ordering::SeqCst);
+    }
+
+    // Number of client thread handles kept for stop to join; finished ones are pruned on each accept.
+    // With a worker pool this is the number of workers.
+    pub fn tracked_client_threads(&self) -> usize {
+        self.client_threads.lock().unwrap().len()
+    }
+
+    // Ask the connection from peer_addr to reconnect: once its current request is answered it is sent
+    // a PleaseReconnect and closed. Returns false if no such connection is live.
+    pub fn quiesce_client(&self, peer_addr: SocketAddr) -> bool {
+        let clients = self.clients.lock().unwrap();
+        match clients.values().find(|client| client.peer_addr == peer_addr) {
+            Some(client) => {
+                client.quiesce.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
     }
 
     pub fn stop(&self) {
-        if self.is_running.load(Ordering::SeqCst) {
-            self.is_running.store(false, Ordering::SeqCst); // Set running flag to false
+        self.shutdown(Duration::ZERO);
+    }
+
+    // Like stop, but clients get up to timeout to finish the request they are on before their streams are
+    // shut down. Idle clients leave within read_timeout. Returns true if every client drained in time,
+    // false if some had to be force-closed.
+    pub fn stop_with_timeout(&self, timeout: Duration) -> bool {
+        self.shutdown(timeout)
+    }
+
+    fn shutdown(&self, drain_timeout: Duration) -> bool {
+        let mut drained = true;
+        // Only the caller that clears the flag shuts down, so concurrent stops never join the same threads twice
+        if self
+            .is_running
+            .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
             info!("Shutdown signal sent.");
+            self.ready.store(false, Ordering::SeqCst);
+            self.wake_accept();
+
+            // Give clients the drain window to notice the flag and leave on their own
+            let deadline = Instant::now() + drain_timeout;
+            while !self.clients.lock().unwrap().is_empty() && Instant::now() < deadline {
+                thread::sleep(self.config.poll_interval);
+            }
 
-            let mut threads = self.client_threads.lock().unwrap(); // Lock threads list(shared resource)
-            for handle in threads.drain(..) {
-                //join all threads 
-                if let Err(e) = handle.join() {
-                    error!("Failed to join thread: {:?}", e); // Log thread join errors
+            // Unblock any client thread still waiting in read, or cut off one still mid-request
+            for client in self.clients.lock().unwrap().values() {
+                if !drain_timeout.is_zero() {
+                    warn!("Client {} did not drain in {:?}, closing it.", client.peer_addr, drain_timeout);
+                }
+                drained = false;
+                client.cancelled.store(true, Ordering::SeqCst); // Stops a paced stream that isn't writing
+                if let Err(e) = client.stream.shutdown(Shutdown::Both) {
+                    debug!("Failed to shutdown client stream: {}", e); // Client may already be gone
                 }
             }
-            info!("All client threads joined.");
+
+            self.join_client_threads();
+            log::logger().flush(); // Client threads have logged their last lines, don't lose them to buffering
         } else {
             warn!("Server was already stopped or not running.");
         }
+        drained
+    }
+
+    // Join every client and worker thread, giving each up to join_timeout. One that is still busy after that,
+    // e.g. stuck in a handler, is left running so it can't hold up shutdown.
+    fn join_client_threads(&self) {
+        let mut threads = self.client_threads.lock().unwrap(); // Lock threads list(shared resource)
+        let mut stuck = 0;
+        for thread in threads.drain(..) {
+            if let Err(RecvTimeoutError::Timeout) = thread.done.recv_timeout(self.config.join_timeout) {
+                warn!(
+                    "Client thread {:?} did not finish within {:?}, leaving it running.",
+                    thread.handle.thread().id(),
+                    self.config.join_timeout
+                );
+                stuck += 1;
+                continue;
+            }
+            //join all threads 
+            if let Err(e) = thread.handle.join() {
+                error!("Failed to join thread: {:?}", e); // Log thread join errors
+            }
+        }
+        if stuck == 0 {
+            info!("All client threads joined.");
+        } else {
+            warn!("Client threads joined, except {} still running.", stuck);
+        }
     }
 
-    pub fn run(&self) -> io::Result<()> {
-        self.is_running.store(true, Ordering::SeqCst); // Set running flag to true
-        info!("Server is running on {}", self.listener.local_addr()?); // Log server address
+    // Whether the lifetime connection limit has been used up
+    fn lifetime_limit_reached(&self) -> bool {
+        self.config
+            .max_lifetime_connections
+            .is_some_and(|max| self.lifetime_connections.load(Ordering::SeqCst) >= max)
+    }
 
-        self.listener.set_nonblocking(true)?; // Set listener to non-blocking mode
+    // No client connected and none waiting for a worker
+    fn is_drained(&self) -> bool {
+        self.clients.lock().unwrap().is_empty() && self.queued_connections.load(Ordering::SeqCst) == 0
+    }
 
-        while self.is_running.load(Ordering::SeqCst) {
-            match self.listener.accept() {
-                Ok((stream, addr)) => {
-                    info!("New client connected: {}", addr); // Log new client connection
-
-                    let is_running = Arc::clone(&self.is_running); // Clone running flag
-                    let client_threads = Arc::clone(&self.client_threads); // Clone threads list
-                    //creating thread for new client
-                    let handle = thread::spawn(move || {
-                        let mut client = Client::new(stream); // Initialize client handler
-                        while is_running.load(Ordering::SeqCst) {
-                            if let Err(e) = client.handle() {
-                                error!("Error handling client: {}", e); // Log client errors
-                                break;
-                            }
-                        }
+    // Count one more served connection against the lifetime limit. Once the limit is used up the listeners
+    // switch to polling, so the accept loops can notice when the last client has left.
+    fn count_lifetime_connection(&self) {
+        let Some(max) = self.config.max_lifetime_connections else {
+            return;
+        };
+        if self.lifetime_connections.fetch_add(1, Ordering::SeqCst) + 1 == max {
+            info!("Served {} connections, refusing new ones and draining.", max);
+            for listener in &self.listeners {
+                if let Err(e) = listener.set_nonblocking(true) {
+                    error!("Failed to switch listener to polling: {}", e);
+                }
+            }
+            self.wake_accept(); // Other accept loops may still be blocked
+        }
+    }
 
-                        if let Err(e) = client.stream.shutdown(std::net::Shutdown::Both) {
-                            error!("Failed to shutdown stream: {}", e); // Log shutdown errors
-                        }
-                    });
+    // Call run on a thread of its own; joining the handle gives back what run returned
+    pub fn run_in_background(self: Arc<Self>) -> JoinHandle<Result<(), ServerError>> {
+        thread::spawn(move || self.run())
+    }
+
+    // Same as run_in_background, but only returns once connections are being accepted, or run has already failed
+    pub fn start_in_background(self: Arc<Self>) -> JoinHandle<Result<(), ServerError>> {
+        let server = Arc::clone(&self);
+        let handle = self.run_in_background();
+        while !server.ready.load(Ordering::SeqCst) && !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle
+    }
+
+    pub fn run(&self) -> Result<(), ServerError> {
+        for listener in &self.listeners {
+            info!("Server is running on {}", listener); // Log server address
+            listener.set_nonblocking(false)?; // Block in accept, stop wakes it with a throwaway connection
+        }
+        self.is_running.store(true, Ordering::SeqCst); // Client threads, the self-test's probe among them, check it
+
+        let queue = self.config.workers.map(|workers| self.spawn_workers(workers));
+
+        if self.config.self_test {
+            // Checking one listener is enough to prove the client path works
+            match self.listeners.iter().find(|listener| matches!(listener, Listener::Tcp(_))) {
+                Some(listener) => {
+                    if let Err(e) = self.self_test(listener, &queue) {
+                        error!("Startup self-test failed: {}", e);
+                        drop(queue); // Let idle workers exit so stop can join them
+                        self.stop();
+                        return Err(e);
+                    }
+                    info!("Startup self-test passed, accepting connections.");
+                }
+                None => warn!("Skipping the startup self-test, which needs a TCP listener to tell its probe apart."),
+            }
+        }
+        self.ready.store(true, Ordering::SeqCst); // Only once every listener is ready and the self-test passed
+
+        // Every listener after the first gets its own accept thread, all feeding the same client path
+        thread::scope(|scope| {
+            for listener in &self.listeners[1..] {
+                scope.spawn(|| self.accept_loop(listener, &queue));
+            }
+            self.accept_loop(&self.listeners[0], &queue);
+        });
+
+        self.ready.store(false, Ordering::SeqCst);
+        drop(queue); // Idle workers see the closed queue and exit
+        if self.lifetime_limit_reached() {
+            self.join_client_threads(); // Stopped by itself, so nobody else will join them
+            info!("Lifetime connection limit served, server stopped.");
+            log::logger().flush(); // The process may exit as soon as run returns
+        }
+        info!("Server stopped."); // Log server stop
+        Ok(())
+    }
 
-                    client_threads.lock().unwrap().push(handle); // Store thread handle so the stop can join each thread
+    // Accept connections on one listener until the server stops
+    fn accept_loop(&self, listener: &Listener, queue: &Option<mpsc::Sender<QueuedClient>>) {
+        let mut backoff = Duration::ZERO; // Grows while accept keeps running out of resources
+        while self.is_running.load(Ordering::SeqCst) {
+            let accepted = listener.accept();
+            if accepted.is_ok() && !backoff.is_zero() {
+                info!("Accepting connections again after running out of resources.");
+                backoff = Duration::ZERO;
+            }
+            match accepted {
+                Ok(_) if !self.is_running.load(Ordering::SeqCst) => break, // The wake-up connection from stop
+                Ok((stream, addr)) if self.lifetime_limit_reached() => {
+                    debug!("Lifetime connection limit reached, refusing {}", addr);
+                    if let Err(e) = stream.shutdown(Shutdown::Both) {
+                        debug!("Failed to shutdown refused stream: {}", e);
+                    }
+                }
+                Ok((stream, addr)) => {
+                    #[cfg(feature = "accept-delay")]
+                    thread::sleep(self.config.accept_delay);
+                    if self.start_client(queue, stream, addr) {
+                        self.count_lifetime_connection();
+                    }
                 }
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(10)); // Wait before retrying
+                    // Only happens while draining after the lifetime limit
+                    if self.is_drained() {
+                        info!("All clients have left, stopping.");
+                        self.is_running.store(false, Ordering::SeqCst);
+                        break;
+                    }
+                    thread::sleep(self.config.poll_interval);
+                }
+                Err(ref e) if is_transient_accept_error(e) => {
+                    debug!("Transient error accepting connection, retrying: {}", e);
+                    thread::sleep(ACCEPT_RETRY_DELAY);
+                }
+                Err(ref e) if is_resource_exhausted(e) => {
+                    // Retrying at once would fail the same way, so back off exponentially and only warn once
+                    if backoff.is_zero() {
+                        warn!("Out of resources accepting connections, backing off: {}", e);
+                        backoff = ACCEPT_ERROR_DELAY;
+                    } else {
+                        debug!("Still out of resources accepting connections: {}", e);
+                        backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    }
+                    thread::sleep(backoff);
                 }
                 Err(e) => {
                     error!("Error accepting connection: {}", e); // Log accept errors
+                    thread::sleep(ACCEPT_ERROR_DELAY); // Don't spin if the error persists
                 }
             }
         }
+    }
 
-        info!("Server stopped."); // Log server stop
+    // Hand a freshly accepted connection to the worker pool, or to a thread of its own.
+    // Returns whether it is being served: false if it was turned away or the peer had already gone, so only
+    // connections that are actually served count towards the lifetime limit.
+    fn start_client(&self, queue: &Option<mpsc::Sender<QueuedClient>>, stream: Stream, addr: SocketAddr) -> bool {
+        self.connections_accepted.fetch_add(1, Ordering::SeqCst);
+        if !self.is_allowed(&stream, addr) {
+            warn!("{} is not on the allowlist, closing connection", addr);
+            if let Err(e) = stream.shutdown(Shutdown::Both) {
+                debug!("Failed to shutdown disallowed stream: {}", e);
+            }
+            return false;
+        }
+        if is_dead_on_arrival(&stream) {
+            debug!("Client {} closed before it was accepted, dropping it.", addr);
+            self.dead_on_accept.fetch_add(1, Ordering::SeqCst);
+            return false; // Nothing to answer, so don't spend a thread or a worker on it
+        }
+        if let Some(max) = self.config.max_buffered_bytes {
+            if self.under_memory_pressure(max) {
+                if let Err(e) = self.reject_full(stream, addr) {
+                    debug!("Failed to shutdown rejected stream: {}", e);
+                }
+                return false;
+            }
+        }
+        if let Some(max) = self.config.max_connections {
+            let connected = self.client_count() + self.queued_connections.load(Ordering::SeqCst);
+            if connected >= max {
+                debug!("Connection limit reached ({} connected)", connected);
+                if let Err(e) = self.reject_full(stream, addr) {
+                    debug!("Failed to shutdown rejected stream: {}", e);
+                }
+                return false;
+            }
+        }
+        info!("New client connected: {}", addr); // Log new client connection
+        if let Some(on_connect) = &self.on_connect {
+            on_connect(addr);
+        }
+
+        let started = match queue {
+            Some(queue) => self.queue_client(queue, stream, addr),
+            None => self.spawn_client(stream, addr, || {}).map(|()| true),
+        };
+        started.unwrap_or_else(|e| {
+            error!("Failed to start client thread for {}: {}", addr, e);
+            false
+        })
+    }
+
+    // Whether the allowlist, if there is one, lets this peer in
+    fn is_allowed(&self, stream: &Stream, addr: SocketAddr) -> bool {
+        match (&self.allowlist, stream) {
+            (None, _) => true,
+            #[cfg(unix)]
+            (Some(_), Stream::Unix(_)) => true, // No peer address to check
+            (Some(allowed), Stream::Tcp(_)) => allowed.contains(&addr.ip().to_canonical()),
+        }
+    }
+
+    // Unblock every accept loop so they see that is_running has been cleared
+    fn wake_accept(&self) {
+        for listener in &self.listeners {
+            if let Err(e) = listener.connect_to_self(SELF_TEST_TIMEOUT) {
+                debug!("Failed to wake the accept loop: {}", e); // run may not be in accept
+            }
+        }
+    }
+
+    // Connect to our own TCP listener, send a ping through the normal client path and check the pong.
+    // Every Unix peer reports the same address, so only a TCP listener can pick the probe out by its own.
+    fn self_test(&self, listener: &Listener, queue: &Option<mpsc::Sender<QueuedClient>>) -> Result<(), ServerError> {
+        let mut probe = listener.connect_to_self(SELF_TEST_TIMEOUT)?;
+        probe.set_read_timeout(Some(SELF_TEST_TIMEOUT))?;
+        let probe_addr = probe.local_addr()?;
+
+        // Accept until the probe comes through; anyone who connected before it is served as usual.
+        // The probe itself skips the allowlist and the callbacks, and is served on a thread of its own so it never
+        // takes a worker. It gets a registry, totals and id of its own, so it never shows up in stats or metrics.
+        loop {
+            let (stream, addr) = listener.accept().map_err(ServerError::Accept)?;
+            if addr == probe_addr {
+                let context = ClientContext {
+                    clients: Arc::new(Mutex::new(HashMap::new())),
+                    active_clients: Arc::new(AtomicUsize::new(0)),
+                    peak_read_ahead: Arc::new(AtomicUsize::new(0)),
+                    totals: Arc::new(TrafficTotals::default()),
+                    on_disconnect: None,
+                    ..self.client_context()
+                };
+                self.spawn_client_with(context, SELF_TEST_CONN_ID, stream, addr, || {})?;
+                break;
+            }
+            self.start_client(queue, stream, addr);
+        }
+
+        let payload = ClientMessage {
+            message: Some(client_message::Message::PingRequest(PingRequest {
+                nonce: SELF_TEST_NONCE,
+            })),
+            close_after_response: true, // The server hangs up on the probe once it has answered
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let no_pong = |e: io::Error| ServerError::SelfTest(format!("probe got no PongResponse: {}", e));
+        probe.write_all(&encode_frame(&payload)).map_err(no_pong)?;
+
+        let mut header = [0; FRAME_HEADER_LEN];
+        probe.read_exact(&mut header).map_err(no_pong)?;
+        let mut payload = vec![0; read_header(&header).expect("Header was read whole")];
+        probe.read_exact(&mut payload).map_err(no_pong)?;
+        let response = ServerMessage::decode(payload.as_slice())?;
+
+        match response.message {
+            Some(server_message::Message::PongResponse(pong)) if pong.nonce == SELF_TEST_NONCE => Ok(()),
+            other => Err(ServerError::SelfTest(format!(
+                "expected a PongResponse with nonce {}, got {:?}",
+                SELF_TEST_NONCE, other
+            ))),
+        }
+    }
+
+    fn client_context(&self) -> ClientContext {
+        ClientContext {
+            config: self.config,
+            is_running: Arc::clone(&self.is_running),
+            request_slots: self.request_slots.clone(),
+            operation_slots: Arc::clone(&self.operation_slots),
+            clients: Arc::clone(&self.clients),
+            active_clients: Arc::clone(&self.active_clients),
+            peak_read_ahead: Arc::clone(&self.peak_read_ahead),
+            handler: Arc::clone(&self.handler),
+            broadcasts_encoded: Arc::clone(&self.broadcasts_encoded),
+            totals: Arc::clone(&self.totals),
+            store: Arc::clone(&self.store),
+            on_disconnect: self.on_disconnect.clone(),
+            started_at: self.started_at,
+        }
+    }
+
+    // Register the client's stream and hand the connection to a new thread, which runs on_exit once it is done
+    fn spawn_client(
+        &self,
+        stream: Stream,
+        peer_addr: SocketAddr,
+        on_exit: impl FnOnce() + Send + 'static,
+    ) -> io::Result<()> {
+        let client_id = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+        self.spawn_client_with(self.client_context(), client_id, stream, peer_addr, on_exit)
+    }
+
+    // Same as spawn_client, serving the connection under the given context and id
+    fn spawn_client_with(
+        &self,
+        context: ClientContext,
+        client_id: ConnId,
+        stream: Stream,
+        peer_addr: SocketAddr,
+        on_exit: impl FnOnce() + Send + 'static,
+    ) -> io::Result<()> {
+        let registration = context.register(client_id, &stream, peer_addr)?;
+        //creating thread for new client
+        let thread = TrackedThread::spawn(move || {
+            context.serve(stream, client_id, peer_addr, registration);
+            on_exit();
+        });
+
+        let mut threads = self.client_threads.lock().unwrap();
+        threads.retain(|thread| !thread.handle.is_finished()); // Drop handles of clients that already left
+        threads.push(thread); // Store thread handle so the stop can join each thread
         Ok(())
     }
+
+    // Hand the connection to the worker pool; it is registered once a worker picks it up.
+    // If the queue is already at its cap the saturation policy decides what happens to it instead.
+    // Ok(false) if it was turned away rather than queued or served.
+    fn queue_client(&self, queue: &mpsc::Sender<QueuedClient>, stream: Stream, peer_addr: SocketAddr) -> io::Result<bool> {
+        let queue_full = || self.queued_connections.load(Ordering::SeqCst) >= self.max_queued_connections.load(Ordering::SeqCst);
+        if queue_full() {
+            match self.config.saturation_policy {
+                SaturationPolicy::Reject => return self.reject_full(stream, peer_addr).map(|()| false),
+                SaturationPolicy::Block => {
+                    debug!("Worker queue full, holding {} until there is room", peer_addr);
+                    while queue_full() {
+                        if !self.is_running.load(Ordering::SeqCst) {
+                            return stream.shutdown(Shutdown::Both).map(|()| false); // Stopped while waiting
+                        }
+                        thread::sleep(self.config.poll_interval);
+                    }
+                }
+                SaturationPolicy::Burst(cap) => {
+                    let has_room = self
+                        .burst_clients
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |burst| (burst < cap).then_some(burst + 1))
+                        .is_ok();
+                    if !has_room {
+                        return self.reject_full(stream, peer_addr).map(|()| false);
+                    }
+                    info!("Worker pool saturated, serving {} on a burst thread", peer_addr);
+                    let burst_clients = Arc::clone(&self.burst_clients);
+                    let started = self.spawn_client(stream, peer_addr, move || {
+                        burst_clients.fetch_sub(1, Ordering::SeqCst);
+                    });
+                    if started.is_err() {
+                        self.burst_clients.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    return started.map(|()| true);
+                }
+            }
+        }
+
+        let client_id = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+        let queued = self.queued_connections.fetch_add(1, Ordering::SeqCst) + 1;
+        self.queue_high_water.fetch_max(queued, Ordering::SeqCst);
+        queue
+            .send((client_id, stream, peer_addr))
+            .map(|()| true)
+            .map_err(|_| {
+                self.queued_connections.fetch_sub(1, Ordering::SeqCst);
+                io::Error::new(ErrorKind::BrokenPipe, "Worker pool has shut down")
+            })
+    }
+
+    // Whether clients hold at least max unprocessed bytes between them. If so the client buffering the most, the most
+    // idle of those on a tie, is closed to free its buffer, one per refused connection, so pressure eases without
+    // dropping everyone at once. Clients holding nothing are never shed, closing them would free nothing.
+    fn under_memory_pressure(&self, max: usize) -> bool {
+        let clients = self.clients.lock().unwrap();
+        let buffered: usize = clients
+            .values()
+            .map(|client| client.counters.buffered_bytes.load(Ordering::SeqCst))
+            .sum();
+        if buffered < max {
+            return false;
+        }
+
+        warn!("Clients are buffering {} bytes, over the {} byte limit.", buffered, max);
+        let largest = clients
+            .values()
+            .map(|client| (client.counters.buffered_bytes.load(Ordering::SeqCst), client))
+            .filter(|&(bytes, _)| bytes > 0)
+            .max_by_key(|&(bytes, client)| (bytes, client.counters.idle()));
+        if let Some((bytes, client)) = largest {
+            warn!("Shedding client {} buffering {} bytes to free memory.", client.peer_addr, bytes);
+            if let Err(e) = client.stream.shutdown(Shutdown::Both) {
+                debug!("Failed to shutdown shed client stream: {}", e);
+            }
+        }
+        true
+    }
+
+    // Tell a connection there is no room for it, then close it
+    fn reject_full(&self, mut stream: Stream, peer_addr: SocketAddr) -> io::Result<()> {
+        warn!("Server full, closing connection from {}", peer_addr);
+        self.rejected_connections.fetch_add(1, Ordering::SeqCst);
+
+        let payload = ServerMessage {
+            message: Some(server_message::Message::ErrorResponse(ErrorResponse {
+                code: ErrorCode::ServerFull.into(),
+                message: "Server is full, try again later".to_string(),
+            })),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        if let Err(e) = stream.write_all(&encode_frame(&payload)) {
+            debug!("Failed to tell {} the server is full: {}", peer_addr, e); // Closed either way
+        }
+        stream.shutdown(Shutdown::Both)
+    }
+
+    // Start the pool workers, which take connections off the returned queue until it is dropped
+    fn spawn_workers(&self, workers: usize) -> mpsc::Sender<QueuedClient> {
+        let (sender, receiver) = mpsc::channel::<QueuedClient>();
+        let receiver = Arc::new(Mutex::new(receiver)); // Shared so each connection goes to exactly one worker
+
+        let mut threads = self.client_threads.lock().unwrap();
+        for _ in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            let context = self.client_context();
+            let queued_connections = Arc::clone(&self.queued_connections);
+            threads.push(TrackedThread::spawn(move || loop {
+                let next = receiver.lock().unwrap().recv(); // Lock released before the connection is served
+                let Ok((client_id, stream, peer_addr)) = next else {
+                    break; // run has returned and dropped the queue
+                };
+                queued_connections.fetch_sub(1, Ordering::SeqCst);
+                match context.register(client_id, &stream, peer_addr) {
+                    Ok(registration) => context.serve(stream, client_id, peer_addr, registration),
+                    Err(e) => error!("Failed to register client {}: {}", peer_addr, e),
+                }
+            }));
+        }
+        sender
+    }
 }
@@ -1,4 +1,5 @@
-use embedded_recruitment_task::message::{client_message, ServerMessage};
+#![allow(dead_code)] // Shared by several test binaries, each using only some of it
+use embedded_recruitment_task::message::{client_message, ClientMessage, ServerMessage};
 use log::error;
 use log::info;
 use prost::Message;
@@ -7,6 +8,7 @@ use std::io::Write;
 use std::{
     io,
     net::{SocketAddr, TcpStream, ToSocketAddrs},
+    thread,
     time::Duration,
 };
 
@@ -14,17 +16,70 @@ use std::{
 pub struct Client {
     ip: String,
     port: u32,
-    timeout: Duration,
+    timeout: Duration, // Connect timeout, per attempt
+    read_timeout: Option<Duration>, // How long receive waits for a response, None to wait forever
+    connect_retries: u32, // Further connect attempts after the first one fails
+    retry_delay: Duration, // Wait before the first retry, doubled for each one after
+    auto_reconnect: bool, // Reconnect and send again once when a send finds the connection broken
     stream: Option<TcpStream>,
 }
 
+// Named settings for a Client, instead of Client::new's positional timeout
+pub struct ClientBuilder {
+    client: Client,
+}
+
+impl ClientBuilder {
+    // how long each connect attempt may take
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.client.timeout = timeout;
+        self
+    }
+
+    // how long receive waits for a response before failing with WouldBlock or TimedOut
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.client.read_timeout = Some(timeout);
+        self
+    }
+
+    // retry a failed connect up to retries more times, waiting delay first and doubling it each time
+    pub fn connect_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.client.connect_retries = retries;
+        self.client.retry_delay = delay;
+        self
+    }
+
+    // when there is no connection, or a send fails because the server closed or reset it, reconnect and send it once more
+    pub fn auto_reconnect(mut self, enabled: bool) -> Self {
+        self.client.auto_reconnect = enabled;
+        self
+    }
+
+    pub fn build(self) -> Client {
+        self.client
+    }
+}
+
 impl Client {
     pub fn new(ip: &str, port: u32, timeout_ms: u64) -> Self {
-        Client {
-            ip: ip.to_string(),
-            port,
-            timeout: Duration::from_millis(timeout_ms),
-            stream: None,
+        Client::builder(ip, port)
+            .connect_timeout(Duration::from_millis(timeout_ms))
+            .build()
+    }
+
+    // start building a client for ip:port, with a 1s connect timeout and no retries or read timeout
+    pub fn builder(ip: &str, port: u32) -> ClientBuilder {
+        ClientBuilder {
+            client: Client {
+                ip: ip.to_string(),
+                port,
+                timeout: Duration::from_secs(1),
+                read_timeout: None,
+                connect_retries: 0,
+                retry_delay: Duration::ZERO,
+                auto_reconnect: false,
+                stream: None,
+            },
         }
     }
 
@@ -43,14 +98,45 @@ impl Client {
             ));
         }
 
-        // Connect to the server with a timeout
-        let stream = TcpStream::connect_timeout(&socket_addrs[0], self.timeout)?;
+        // Connect to the server with a timeout, retrying with backoff if configured
+        let mut delay = self.retry_delay;
+        let mut retries_left = self.connect_retries;
+        let stream = loop {
+            match TcpStream::connect_timeout(&socket_addrs[0], self.timeout) {
+                Ok(stream) => break stream,
+                Err(e) if retries_left > 0 => {
+                    println!("Connect failed ({}), retrying in {:?}", e, delay);
+                    thread::sleep(delay);
+                    delay *= 2;
+                    retries_left -= 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        stream.set_read_timeout(self.read_timeout)?;
         self.stream = Some(stream);
 
         println!("Connected to the server!");
         Ok(())
     }
 
+    // drop the current connection, if any, and open a fresh one to the same host and port
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(std::net::Shutdown::Both); // It may already be broken, which is why we reconnect
+        }
+        println!("Reconnecting to {}:{}", self.ip, self.port);
+        self.connect()
+    }
+
+    // local address of the connection, which the server sees as the peer address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.stream {
+            Some(stream) => stream.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected")),
+        }
+    }
+
     // disconnect the client
     pub fn disconnect(&mut self) -> io::Result<()> {
         if let Some(stream) = self.stream.take() {
@@ -63,17 +149,42 @@ impl Client {
 
     // generic message to send message to the server
     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
-        if let Some(ref mut stream) = self.stream {
-            // Encode the message to a buffer
-            let mut buffer = Vec::new();
-            message.encode(&mut buffer);
+        self.send_message(ClientMessage {
+            message: Some(message),
+            ..Default::default()
+        })
+    }
+
+    // send a full ClientMessage, including its per-request options
+    pub fn send_message(&mut self, message: ClientMessage) -> io::Result<()> {
+        // Encode the message to a buffer
+        let buffer = message.encode_to_vec();
 
-            // Send the buffer to the server
-            stream.write_all(&buffer)?;
-            stream.flush()?;
+        // Prefix the payload with its length as a big-endian u32
+        let mut frame = (buffer.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&buffer);
 
-            println!("Sent message: {:?}", message);
-            Ok(())
+        // Send the frame to the server, on a new connection if there is none or the old one broke and auto-reconnect is on
+        if self.stream.is_none() && self.auto_reconnect {
+            self.reconnect()?;
+        }
+        match self.write_frame(&frame) {
+            Err(ref e) if self.auto_reconnect && is_connection_broken(e) => {
+                println!("Send failed ({}), reconnecting", e);
+                self.reconnect()?;
+                self.write_frame(&frame)?;
+            }
+            result => result?,
+        }
+
+        println!("Sent message: {:?}", message);
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(ref mut stream) = self.stream {
+            stream.write_all(frame)?;
+            stream.flush()
         } else {
             Err(io::Error::new(
                 io::ErrorKind::NotConnected,
@@ -85,20 +196,24 @@ impl Client {
     pub fn receive(&mut self) -> io::Result<ServerMessage> {
         if let Some(ref mut stream) = self.stream {
             info!("Receiving message from the server");
-            let mut buffer = vec![0u8; 1024];
-            let bytes_read = stream.read(&mut buffer)?;
-            if bytes_read == 0 {
-                info!("Server disconnected.");
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Server disconnected",
-                ));
-            }
 
-            info!("Received {} bytes from the server", bytes_read);
+            // Read the length prefix, then exactly that many payload bytes
+            let mut header = [0u8; 4];
+            stream.read_exact(&mut header).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    info!("Server disconnected.");
+                    io::Error::new(io::ErrorKind::ConnectionAborted, "Server disconnected")
+                }
+                _ => e,
+            })?;
+            let length = u32::from_be_bytes(header) as usize;
+            let mut buffer = vec![0u8; length];
+            stream.read_exact(&mut buffer)?;
+
+            info!("Received {} bytes from the server", length);
 
             // Decode the received message
-            ServerMessage::decode(&buffer[..bytes_read]).map_err(|e| {
+            ServerMessage::decode(buffer.as_slice()).map_err(|e| {
                 io::Error::new(
                     io::ErrorKind::InvalidData,
                     format!("Failed to decode ServerMessage: {}", e),
@@ -113,3 +228,11 @@ impl Client {
         }
     }
 }
+
+// whether a write failed because the server closed or reset the connection
+fn is_connection_broken(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+    )
+}
@@ -1,42 +1,167 @@
 use embedded_recruitment_task::{
-    message::{client_message, server_message, AddRequest, EchoMessage},
-    server::Server,
+    message::{
+        client_message, server_message, AddRequest, BroadcastMessage, ClientMessage, DivideRequest, EchoMessage, ErrorCode,
+        GetRequest, MultiplyRequest, PingRequest, QuitRequest, RangeRequest, ReverseBytesRequest, ServerInfoRequest, SetRequest, StatsCalcRequest, StatsRequest,
+        ServerMessage, StreamEchoRequest, SubtractRequest,
+    },
+    error::ServerError,
+    handler::{DefaultHandler, MessageHandler},
+    server::{SaturationPolicy, Server},
 };
+use prost::Message;
 use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
     sync::Arc,
-    thread::{self, JoinHandle},
+    thread,
+    time::{Duration, Instant},
 };
-use serial_test::serial;
 mod client;
+mod support;
+use support::{assert_ping, create_server, server_port, setup_server_thread, start_saturated_server, wait_for};
 
-fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
-    thread::spawn(move || {
-        server.run().expect("Server encountered an error");
-    })
+#[test]
+//#[ignore = "please remove ignore and fix this test"]
+fn test_client_connection() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::builder("localhost", port)
+        .connect_timeout(Duration::from_secs(1))
+        .build();
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_wait_until_ready_then_connect() {
+    // Not ready before run is called
+    let server = create_server();
+    assert!(!server.wait_until_ready(Duration::from_millis(50)), "Server is not running yet");
+
+    // Once ready, a client can connect and be served without any sleep
+    let running = server.clone();
+    let handle = thread::spawn(move || running.run().expect("Server encountered an error"));
+    assert!(server.wait_until_ready(Duration::from_secs(1)), "Server never became ready");
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "ready".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive echo").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "ready"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
 }
 
-fn create_server() -> Arc<Server> {
-  
-    Arc::new(Server::new("localhost:8080").expect("Failed to start server"))
+#[test]
+fn test_start_in_background() {
+    // No thread or wait of our own: the server is accepting as soon as this returns
+    let server = create_server();
+    let handle = server.clone().start_in_background();
+    assert!(server.is_running(), "Server should be running once start_in_background returns");
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut client, 1);
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and collect what run returned
+    server.stop();
+    let result = handle.join().expect("Server thread panicked");
+    assert!(result.is_ok(), "Server run failed: {:?}", result);
 }
 
 #[test]
-#[serial]
-//#[ignore = "please remove ignore and fix this test"]
-fn test_client_connection() {
-    // Set up the server in a separate thread
+fn test_is_running_follows_run_and_stop() {
+    // A freshly constructed server is bound but not running
     let server = create_server();
+    assert!(!server.is_running(), "Server should not be running before run");
+
+    // Set up the server in a separate thread
+    let handle = setup_server_thread(server.clone());
+    assert!(server.is_running(), "Server should be running once run has started");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(!server.is_running(), "Server should not be running after stop");
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_connect_and_disconnect_callbacks() {
+    // Record the peer address of every connection and disconnection
+    let connected = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let disconnected = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut server = Server::new("localhost:0").expect("Failed to start server");
+    {
+        let connected = connected.clone();
+        server.on_connect(move |addr| connected.lock().unwrap().push(addr));
+        let disconnected = disconnected.clone();
+        server.on_disconnect(move |addr| disconnected.lock().unwrap().push(addr));
+    }
+
+    // Set up the server in a separate thread
+    let server = Arc::new(server);
     let handle = setup_server_thread(server.clone());
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let client_addr = client.local_addr().expect("Failed to read client address");
+    assert!(
+        wait_for(Duration::from_secs(1), || connected.lock().unwrap().contains(&client_addr)),
+        "on_connect was not called with the client's address"
+    );
+    assert!(disconnected.lock().unwrap().is_empty(), "on_disconnect called while still connected");
 
     // Disconnect the client
     assert!(
         client.disconnect().is_ok(),
         "Failed to disconnect from the server"
     );
+    assert!(
+        wait_for(Duration::from_secs(1), || *disconnected.lock().unwrap() == vec![client_addr]),
+        "on_disconnect was not called with the client's address"
+    );
 
     // Stop the server and wait for thread to finish
     server.stop();
@@ -47,20 +172,21 @@ fn test_client_connection() {
 }
 
 #[test]
-#[serial]
 //#[ignore = "please remove ignore and fix this test"]
 fn test_client_echo_message() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
-    let mut echo_message = EchoMessage::default();
-    echo_message.content = "Hello, World!".to_string();
+    let echo_message = EchoMessage {
+        content: "Hello, World!".to_string(),
+    };
     let message = client_message::Message::EchoMessage(echo_message.clone());
 
     // Send the message to the server
@@ -99,15 +225,60 @@ fn test_client_echo_message() {
 }
 
 #[test]
-#[serial]
+fn test_client_reconnects_on_the_same_instance() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let echo = |client: &mut client::Client, content: &str| {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        match client.receive().expect("Failed to receive echo").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    };
+
+    // Echo, disconnect, then reconnect explicitly and echo again
+    let mut client = client::Client::builder("localhost", port).auto_reconnect(true).build();
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    echo(&mut client, "first");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(client.reconnect().is_ok(), "Failed to reconnect to the server");
+    echo(&mut client, "second");
+
+    // With auto-reconnect, a send while disconnected opens a new connection itself
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    echo(&mut client, "third");
+    assert_eq!(server.stats().connections_accepted, 3, "Each reconnect should be a new connection");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
 //#[ignore = "please remove ignore and fix this test"]
 fn test_multiple_echo_messages() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
     
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare multiple messages
@@ -119,8 +290,9 @@ fn test_multiple_echo_messages() {
 
     // Send and receive multiple messages
     for message_content in messages {
-        let mut echo_message = EchoMessage::default();
-        echo_message.content = message_content.clone();
+        let echo_message = EchoMessage {
+            content: message_content.clone(),
+        };
         let message = client_message::Message::EchoMessage(echo_message);
 
         // Send the message to the server
@@ -156,22 +328,21 @@ fn test_multiple_echo_messages() {
         handle.join().is_ok(),
         "Server thread panicked or failed to join"
     );
-    thread::sleep(std::time::Duration::from_millis(100));
 }
 
 #[test]
-#[serial]
 //#[ignore = "please remove ignore and fix this test"]
 fn test_multiple_clients() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect multiple clients
-    let mut clients = vec![
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
-        client::Client::new("localhost", 8080, 1000),
+    let mut clients = [
+        client::Client::new("localhost", port, 1000),
+        client::Client::new("localhost", port, 1000),
+        client::Client::new("localhost", port, 1000),
     ];
 
     for client in clients.iter_mut() {
@@ -187,8 +358,9 @@ fn test_multiple_clients() {
 
     // Send and receive multiple messages for each client
     for message_content in messages {
-        let mut echo_message = EchoMessage::default();
-        echo_message.content = message_content.clone();
+        let echo_message = EchoMessage {
+            content: message_content.clone(),
+        };
         let message = client_message::Message::EchoMessage(echo_message.clone());
 
         for client in clients.iter_mut() {
@@ -234,22 +406,20 @@ fn test_multiple_clients() {
 }
 
 #[test]
-#[serial]
 //#[ignore = "please remove ignore and fix this test"]
 fn test_client_add_request() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message
-    let mut add_request = AddRequest::default();
-    add_request.a = 10;
-    add_request.b = 20;
-    let message = client_message::Message::AddRequest(add_request.clone());
+    let add_request = AddRequest { a: 10, b: 20 };
+    let message = client_message::Message::AddRequest(add_request);
 
     // Send the message to the server
     assert!(client.send(message).is_ok(), "Failed to send message");
@@ -286,23 +456,20 @@ fn test_client_add_request() {
     );
 }
 
-
 #[test]
-#[serial]
 fn test_client_add_request_with_negative_numbers() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare the message with negative numbers
-    let mut add_request = AddRequest::default();
-    add_request.a = -10;
-    add_request.b = -20;
-    let message = client_message::Message::AddRequest(add_request.clone());
+    let add_request = AddRequest { a: -10, b: -20 };
+    let message = client_message::Message::AddRequest(add_request);
 
     // Send the message to the server
     assert!(client.send(message).is_ok(), "Failed to send message");
@@ -340,15 +507,15 @@ fn test_client_add_request_with_negative_numbers() {
 }
 
 #[test]
-#[serial]
 //#[ignore = "please remove ignore and fix this test"]
 fn test_client_send_message_after_disconnect() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect the client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Disconnect the client
@@ -358,8 +525,9 @@ fn test_client_send_message_after_disconnect() {
     );
 
     // Try sending a message after disconnection
-    let mut echo_message = EchoMessage::default();
-    echo_message.content = "Hello, Server!".to_string();
+    let echo_message = EchoMessage {
+        content: "Hello, Server!".to_string(),
+    };
     let message = client_message::Message::EchoMessage(echo_message);
 
     // Assert that sending a message after disconnect fails
@@ -376,17 +544,16 @@ fn test_client_send_message_after_disconnect() {
     );
 }
 
-
 #[test]
-#[serial]
 //#[ignore = "please remove ignore and fix this test"]
 fn test_server_handling_large_number_of_messages() {
     // Set up the server in a separate thread
     let server = create_server();
     let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
 
     // Create and connect a single client
-    let mut client = client::Client::new("localhost", 8080, 1000);
+    let mut client = client::Client::new("localhost", port, 1000);
     assert!(client.connect().is_ok(), "Failed to connect to the server");
 
     // Prepare a large number of echo messages to send
@@ -395,8 +562,9 @@ fn test_server_handling_large_number_of_messages() {
 
     // Send a large number of messages to the server
     for i in 0..num_messages {
-        let mut echo_message = EchoMessage::default();
-        echo_message.content = format!("Test Message {}", i);
+        let echo_message = EchoMessage {
+            content: format!("Test Message {}", i),
+        };
         let message = client_message::Message::EchoMessage(echo_message);
 
         // Send the message to the server
@@ -439,3 +607,3655 @@ fn test_server_handling_large_number_of_messages() {
     );
 }
 
+#[test]
+fn test_client_stream_echo_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Ask for five echoes paced 50ms apart
+    let stream_request = StreamEchoRequest {
+        content: "Streamed".to_string(),
+        count: 5,
+        interval_ms: 50,
+    };
+    let message = client_message::Message::StreamEchoRequest(stream_request);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Each echo should arrive as a separate response
+    for i in 0..5 {
+        let response = client.receive();
+        assert!(
+            response.is_ok(),
+            "Failed to receive streamed echo {}",
+            i
+        );
+
+        match response.unwrap().message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, "Streamed", "Streamed echo content does not match");
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stream_echo_rejects_out_of_bounds_requests() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Too many echoes, or too long a pause between them, is refused up front
+    for (count, interval_ms) in [(1001, 0), (2, u32::MAX)] {
+        let message = client_message::Message::StreamEchoRequest(StreamEchoRequest {
+            content: "Unbounded".to_string(),
+            count,
+            interval_ms,
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::ErrorResponse(error)) => {
+                assert_eq!(error.code(), ErrorCode::InvalidRange, "Expected an INVALID_RANGE error");
+            }
+            _ => panic!("Expected ErrorResponse, but received a different message"),
+        }
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_stats_calc_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare the message
+    let stats_request = StatsCalcRequest {
+        values: vec![2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0],
+    };
+    let message = client_message::Message::StatsCalcRequest(stats_request);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for StatsCalcRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::StatsCalcResponse(stats)) => {
+            assert_eq!(stats.count, 8, "StatsCalcResponse count does not match");
+            assert!((stats.mean - 5.0).abs() < 1e-9, "Unexpected mean {}", stats.mean);
+            // Sample standard deviation of the values is sqrt(32 / 7)
+            assert!(
+                (stats.stddev - (32.0f64 / 7.0).sqrt()).abs() < 1e-9,
+                "Unexpected stddev {}",
+                stats.stddev
+            );
+        }
+        _ => panic!("Expected StatsCalcResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_stats_calc_request_single_value() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare the message
+    let stats_request = StatsCalcRequest {
+        values: vec![42.5],
+    };
+    let message = client_message::Message::StatsCalcRequest(stats_request);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for StatsCalcRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::StatsCalcResponse(stats)) => {
+            assert_eq!(stats.count, 1, "StatsCalcResponse count does not match");
+            assert!((stats.mean - 42.5).abs() < 1e-9, "Unexpected mean {}", stats.mean);
+            assert_eq!(stats.stddev, 0.0, "A single value should have a stddev of 0");
+        }
+        _ => panic!("Expected StatsCalcResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_stats_calc_request_empty_list() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare the message
+    let stats_request = StatsCalcRequest {
+        values: Vec::new(),
+    };
+    let message = client_message::Message::StatsCalcRequest(stats_request);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for StatsCalcRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::EmptyList, "Expected an EMPTY_LIST error");
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_large_echo_message() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare a 10 KB message, well over a single read's worth of data
+    let content: String = (0..10 * 1024)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    let echo_message = EchoMessage {
+        content: content.clone(),
+    };
+    let message = client_message::Message::EchoMessage(echo_message);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the echoed message
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for large EchoMessage"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content.len(), content.len(), "Echoed message length does not match");
+            assert_eq!(echo.content, content, "Echoed message content does not match");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_server_handling_pipelined_messages() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect a single client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Send every message before reading any reply, so frames share and straddle reads
+    let num_messages = 500;
+    for i in 0..num_messages {
+        let echo_message = EchoMessage {
+            content: format!("Pipelined Message {}", i),
+        };
+        let message = client_message::Message::EchoMessage(echo_message);
+
+        assert!(client.send(message).is_ok(), "Failed to send message {}", i);
+    }
+
+    // Every echo must come back intact and in order
+    for i in 0..num_messages {
+        let response = client.receive();
+        assert!(
+            response.is_ok(),
+            "Failed to receive response for EchoMessage {}",
+            i
+        );
+
+        match response.unwrap().message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(
+                    echo.content,
+                    format!("Pipelined Message {}", i),
+                    "Echoed message content does not match"
+                );
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_server_with_large_buffer_size() {
+    // Set up a server reading into a 64 KB buffer
+    let server = Arc::new(
+        Server::with_buffer_size("localhost:0", 64 * 1024).expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare a message that nearly fills the buffer
+    let content = "x".repeat(60 * 1024);
+    let echo_message = EchoMessage {
+        content: content.clone(),
+    };
+    let message = client_message::Message::EchoMessage(echo_message);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the echoed message
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for large EchoMessage"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, content, "Echoed message content does not match");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_server_with_tiny_buffer_size() {
+    // Set up a server reading 16 bytes at a time, so every frame spans several reads
+    let server = Arc::new(
+        Server::with_buffer_size("localhost:0", 16).expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect a single client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Send a large number of rapid messages
+    let num_messages = 500;
+    for i in 0..num_messages {
+        let echo_message = EchoMessage {
+            content: format!("Test Message {}", i),
+        };
+        let message = client_message::Message::EchoMessage(echo_message);
+
+        // Send the message to the server
+        assert!(client.send(message).is_ok(), "Failed to send message {}", i);
+
+        // Receive the echoed message
+        let response = client.receive();
+        assert!(
+            response.is_ok(),
+            "Failed to receive response for EchoMessage {}",
+            i
+        );
+
+        match response.unwrap().message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(
+                    echo.content,
+                    format!("Test Message {}", i),
+                    "Echoed message content does not match"
+                );
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_message_written_one_byte_at_a_time_still_echoes() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Dribble a framed echo in one byte per write, so every read sees only a fragment
+    let payload = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "Sent in pieces".to_string(),
+        })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream.set_nodelay(true).expect("Failed to set TCP_NODELAY");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set read timeout");
+    for byte in &frame {
+        stream.write_all(&[*byte]).expect("Failed to send byte");
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    // The bytes are kept until the frame is complete, then decoded and echoed
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).expect("Failed to read response header");
+    let mut response = vec![0u8; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut response).expect("Failed to read response");
+    match ServerMessage::decode(response.as_slice()).expect("Failed to decode response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "Sent in pieces"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_close_after_response() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare a one-shot request
+    let echo_message = EchoMessage {
+        content: "One shot".to_string(),
+    };
+    let message = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(echo_message)),
+        close_after_response: true,
+        ..Default::default()
+    };
+
+    // Send the message to the server
+    assert!(client.send_message(message).is_ok(), "Failed to send message");
+
+    // The response still arrives
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for one-shot EchoMessage"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "One shot", "Echoed message content does not match");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Then the server closes its side
+    assert!(
+        client.receive().is_err(),
+        "Server should close the connection after a one-shot response"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_blocking_reads_keep_round_trip_latency_low() {
+    // Set up a server whose client reads block for up to 50ms at a time
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .read_timeout(Duration::from_millis(50))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Time a series of request/response round trips
+    let num_messages: u32 = 100;
+    let start = Instant::now();
+    for i in 0..num_messages {
+        let echo_message = EchoMessage {
+            content: format!("Latency {}", i),
+        };
+        let message = client_message::Message::EchoMessage(echo_message);
+
+        assert!(client.send(message).is_ok(), "Failed to send message {}", i);
+        assert!(client.receive().is_ok(), "Failed to receive response {}", i);
+    }
+    let average = start.elapsed() / num_messages;
+
+    // Polling with a 10ms sleep made every round trip cost up to 10ms
+    assert!(
+        average < Duration::from_millis(5),
+        "Average round trip took {:?}",
+        average
+    );
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_subtract_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare the message
+    let subtract_request = SubtractRequest { a: 20, b: 5 };
+    let message = client_message::Message::SubtractRequest(subtract_request);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for SubtractRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::SubtractResponse(subtract_response)) => {
+            assert_eq!(
+                subtract_response.result, 15,
+                "SubtractResponse result does not match"
+            );
+        }
+        _ => panic!("Expected SubtractResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_multiply_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare a product that would overflow i32
+    let multiply_request = MultiplyRequest { a: 100000, b: 100000 };
+    let message = client_message::Message::MultiplyRequest(multiply_request);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for MultiplyRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::MultiplyResponse(multiply_response)) => {
+            assert_eq!(
+                multiply_response.result, 10_000_000_000,
+                "MultiplyResponse result does not match"
+            );
+        }
+        _ => panic!("Expected MultiplyResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_divide_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Send the message to the server
+    let message = client_message::Message::DivideRequest(DivideRequest { a: 10, b: 2 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for DivideRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::DivideResponse(divide_response)) => {
+            assert_eq!(divide_response.result, 5, "DivideResponse result does not match");
+        }
+        _ => panic!("Expected DivideResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_divide_by_zero_returns_error_and_keeps_connection() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Dividing by zero is answered with a defined error
+    let message = client_message::Message::DivideRequest(DivideRequest { a: 10, b: 0 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::DivisionByZero);
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // The connection is still served afterwards
+    assert_ping(&mut client, 36);
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_range_request_streams_each_value() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // One request, one echo per value in order
+    let message = client_message::Message::RangeRequest(RangeRequest { start: 1, end: 5 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    for expected in 1..=5 {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, expected.to_string(), "Range values arrived out of order");
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+
+    // Nothing follows the last value
+    assert_ping(&mut client, 5);
+
+    // A backwards range is an error rather than no answer at all
+    let message = client_message::Message::RangeRequest(RangeRequest { start: 5, end: 1 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::InvalidRange);
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_key_value_store_is_shared_between_clients() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Connect two clients
+    let mut writer = client::Client::new("localhost", port, 1000);
+    assert!(writer.connect().is_ok(), "Failed to connect to the server");
+    let mut reader = client::Client::new("localhost", port, 1000);
+    assert!(reader.connect().is_ok(), "Failed to connect to the server");
+
+    // The first client stores a value and waits for the acknowledgement
+    let message = client_message::Message::SetRequest(SetRequest {
+        key: "greeting".to_string(),
+        value: "hello".to_string(),
+    });
+    assert!(writer.send(message).is_ok(), "Failed to send message");
+    match writer.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::SetResponse(_)) => {}
+        _ => panic!("Expected SetResponse, but received a different message"),
+    }
+
+    // The second client reads it back, and sees nothing for a key no one set
+    for (key, expected) in [("greeting", Some("hello".to_string())), ("missing", None)] {
+        let message = client_message::Message::GetRequest(GetRequest { key: key.to_string() });
+        assert!(reader.send(message).is_ok(), "Failed to send message");
+        match reader.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::GetResponse(get_response)) => {
+                assert_eq!(get_response.value, expected, "Unexpected value for key {}", key);
+            }
+            _ => panic!("Expected GetResponse, but received a different message"),
+        }
+    }
+
+    // Disconnect the clients
+    for client in [&mut writer, &mut reader] {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_ping_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare the message
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 42 });
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    let response = client.receive();
+    assert!(response.is_ok(), "Failed to receive response for PingRequest");
+
+    match response.unwrap().message {
+        Some(server_message::Message::PongResponse(pong_response)) => {
+            assert_eq!(pong_response.nonce, 42, "PongResponse nonce does not match");
+        }
+        _ => panic!("Expected PongResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_reverse_bytes_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare the message, including bytes that are not valid UTF-8
+    let data = vec![0x00, 0x01, 0xC3, 0xA9, 0xFF, 0xFE, 0x80, 0x7F];
+    let message = client_message::Message::ReverseBytesRequest(ReverseBytesRequest { data });
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for ReverseBytesRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::ReverseBytesResponse(reverse_response)) => {
+            assert_eq!(
+                reverse_response.data,
+                vec![0x7F, 0x80, 0xFE, 0xFF, 0xA9, 0xC3, 0x01, 0x00],
+                "Bytes were not reversed exactly"
+            );
+        }
+        _ => panic!("Expected ReverseBytesResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_stats_request_counts_messages() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Send two echoes first
+    for i in 0..2 {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("Counted {}", i),
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        assert!(client.receive().is_ok(), "Failed to receive response");
+    }
+
+    // Ask how many messages the server has seen on this connection
+    let message = client_message::Message::StatsRequest(StatsRequest {});
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    let response = client.receive();
+    assert!(response.is_ok(), "Failed to receive response for StatsRequest");
+
+    match response.unwrap().message {
+        Some(server_message::Message::StatsResponse(stats_response)) => {
+            // Two echoes plus the StatsRequest itself
+            assert_eq!(stats_response.message_count, 3, "StatsResponse count does not match");
+        }
+        _ => panic!("Expected StatsResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_server_info_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Uptime is in whole seconds, so let one pass
+    thread::sleep(Duration::from_millis(1100));
+    let message = client_message::Message::ServerInfoRequest(ServerInfoRequest {});
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ServerInfoResponse(info)) => {
+            assert!(info.uptime_secs >= 1, "Uptime should be non-zero");
+            assert!(!info.version.is_empty(), "Version should be set");
+            assert_eq!(info.active_connections, 1);
+        }
+        _ => panic!("Expected ServerInfoResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_invalid_utf8_echo_gets_error_reply() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Encode an echo, then swap its content for bytes that are not UTF-8
+    let mut payload = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "@@".to_string(),
+        })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let content_at = payload.windows(2).position(|bytes| bytes == b"@@").unwrap();
+    payload[content_at..content_at + 2].copy_from_slice(&[0xC3, 0x28]);
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set read timeout");
+    stream.write_all(&frame).expect("Failed to send frame");
+
+    // The server answers with a structured error instead of only logging it
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).expect("Failed to read response header");
+    let mut response = vec![0u8; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut response).expect("Failed to read response");
+    match ServerMessage::decode(response.as_slice()).expect("Failed to decode response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::InvalidUtf8);
+            assert!(error.message.contains("UTF-8"), "Unexpected message: {}", error.message);
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_quit_request_is_acknowledged_then_closed() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut client, 1);
+
+    // Quitting gets an acknowledgment
+    let message = client_message::Message::QuitRequest(QuitRequest {});
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::QuitResponse(_)) => {}
+        _ => panic!("Expected QuitResponse, but received a different message"),
+    }
+
+    // Then the server closes the connection from its side
+    let err = client.receive().expect_err("Server kept the connection open after QuitResponse");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted, "Unexpected error: {}", err);
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 0),
+        "Server still counts the client that quit"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_global_request_limit_sheds_excess_requests() {
+    // Set up a server that processes one request at a time and never queues for long
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .max_concurrent_requests(1, Duration::from_millis(50))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect two clients
+    let mut slow_client = client::Client::new("localhost", port, 1000);
+    let mut other_client = client::Client::new("localhost", port, 1000);
+    assert!(slow_client.connect().is_ok(), "Failed to connect to the server");
+    assert!(other_client.connect().is_ok(), "Failed to connect to the server");
+
+    // Occupy the only slot with a slow streamed request
+    let stream_request = StreamEchoRequest {
+        content: "Slow".to_string(),
+        count: 4,
+        interval_ms: 100,
+    };
+    assert!(
+        slow_client
+            .send(client_message::Message::StreamEchoRequest(stream_request))
+            .is_ok(),
+        "Failed to send message"
+    );
+    assert!(slow_client.receive().is_ok(), "Failed to receive streamed echo 0"); // The slot is held from here on
+
+    // A request arriving meanwhile is shed once its wait runs out
+    let echo_message = EchoMessage {
+        content: "Excess".to_string(),
+    };
+    let message = client_message::Message::EchoMessage(echo_message);
+    assert!(other_client.send(message.clone()).is_ok(), "Failed to send message");
+
+    match other_client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::Overloaded, "Expected an OVERLOADED error");
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // The slow request itself completes normally
+    for i in 1..4 {
+        assert!(slow_client.receive().is_ok(), "Failed to receive streamed echo {}", i);
+    }
+
+    // Once the slot is free, requests are served again
+    assert!(other_client.send(message).is_ok(), "Failed to send message");
+    match other_client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "Excess", "Echoed message content does not match");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Disconnect the clients
+    assert!(slow_client.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(other_client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_global_request_limit_queues_within_wait_timeout() {
+    // Set up a server that processes one request at a time but lets requests wait up to 2s
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .max_concurrent_requests(1, Duration::from_secs(2))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect two clients
+    let mut slow_client = client::Client::new("localhost", port, 1000);
+    let mut other_client = client::Client::new("localhost", port, 1000);
+    assert!(slow_client.connect().is_ok(), "Failed to connect to the server");
+    assert!(other_client.connect().is_ok(), "Failed to connect to the server");
+
+    // Occupy the only slot for roughly 300ms
+    let stream_request = StreamEchoRequest {
+        content: "Slow".to_string(),
+        count: 4,
+        interval_ms: 100,
+    };
+    assert!(
+        slow_client
+            .send(client_message::Message::StreamEchoRequest(stream_request))
+            .is_ok(),
+        "Failed to send message"
+    );
+    assert!(slow_client.receive().is_ok(), "Failed to receive streamed echo 0"); // The slot is held from here on
+
+    // The second request queues behind the slow one instead of failing
+    let start = Instant::now();
+    let echo_message = EchoMessage {
+        content: "Queued".to_string(),
+    };
+    assert!(
+        other_client
+            .send(client_message::Message::EchoMessage(echo_message))
+            .is_ok(),
+        "Failed to send message"
+    );
+
+    match other_client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "Queued", "Echoed message content does not match");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    assert!(
+        start.elapsed() >= Duration::from_millis(150),
+        "Queued request should have waited for the slow one, took {:?}",
+        start.elapsed()
+    );
+
+    for i in 1..4 {
+        assert!(slow_client.receive().is_ok(), "Failed to receive streamed echo {}", i);
+    }
+
+    // Disconnect the clients
+    assert!(slow_client.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(other_client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_add_request_overflow() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare an addition that overflows i64
+    let add_request = AddRequest { a: i64::MAX, b: 1 };
+    let message = client_message::Message::AddRequest(add_request);
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // The server answers with an error instead of dropping the connection
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for overflowing AddRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::Overflow, "Expected an OVERFLOW error");
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // The connection is still usable afterwards
+    let add_request = AddRequest { a: 1, b: 2 };
+    assert!(
+        client.send(client_message::Message::AddRequest(add_request)).is_ok(),
+        "Failed to send message"
+    );
+
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::AddResponse(add_response)) => {
+            assert_eq!(add_response.result, 3, "AddResponse result does not match");
+        }
+        _ => panic!("Expected AddResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_add_request_beyond_i32() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare an addition of two values that don't fit in i32
+    let a = i64::from(i32::MAX) + 1;
+    let b = i64::from(i32::MAX) + 10;
+    let message = client_message::Message::AddRequest(AddRequest { a, b });
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the response
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::AddResponse(add_response)) => {
+            assert_eq!(add_response.result, 4_294_967_305, "AddResponse result does not match");
+        }
+        _ => panic!("Expected AddResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_dry_run_validates_without_executing() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Dry runs of requests that would succeed are acknowledged instead of answered
+    let succeeding = [
+        client_message::Message::AddRequest(AddRequest { a: 1, b: 2 }),
+        client_message::Message::SetRequest(SetRequest {
+            key: "dry".to_string(),
+            value: "run".to_string(),
+        }),
+        client_message::Message::BroadcastMessage(BroadcastMessage {
+            content: "Not sent".to_string(),
+        }),
+    ];
+    for message in succeeding {
+        let message = ClientMessage {
+            message: Some(message),
+            dry_run: true,
+            ..Default::default()
+        };
+        assert!(client.send_message(message).is_ok(), "Failed to send message");
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::AckResponse(_)) => {}
+            _ => panic!("Expected AckResponse, but received a different message"),
+        }
+    }
+
+    // A dry run of a request that would fail gets the error it would have caused
+    let message = ClientMessage {
+        message: Some(client_message::Message::AddRequest(AddRequest { a: i64::MAX, b: 1 })),
+        dry_run: true,
+        ..Default::default()
+    };
+    assert!(client.send_message(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::Overflow, "Expected an OVERFLOW error");
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // An empty message fails validation with the same code running it gives
+    let message = ClientMessage {
+        dry_run: true,
+        ..Default::default()
+    };
+    assert!(client.send_message(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::EmptyMessage, "Expected an EMPTY_MESSAGE error");
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // Nothing was stored or broadcast
+    let message = client_message::Message::GetRequest(GetRequest { key: "dry".to_string() });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::GetResponse(get_response)) => {
+            assert_eq!(get_response.value, None, "Dry-run SetRequest stored a value");
+        }
+        _ => panic!("Expected GetResponse, but received a different message"),
+    }
+    assert_eq!(server.stats().broadcasts_encoded, 0, "Dry-run BroadcastMessage was broadcast");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_empty_message_gets_error_reply() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Every empty message is answered with an EMPTY_MESSAGE error, up to the tenth
+    for i in 1..=10 {
+        assert!(client.send_message(ClientMessage::default()).is_ok(), "Failed to send message");
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::ErrorResponse(error)) => {
+                assert_eq!(error.code(), ErrorCode::EmptyMessage, "Expected an EMPTY_MESSAGE error for message {}", i);
+            }
+            _ => panic!("Expected ErrorResponse, but received a different message"),
+        }
+    }
+
+    // After which the server gives up on the connection
+    assert!(client.receive().is_err(), "Connection should be closed after repeated empty messages");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_rate_limit_throttles_fast_client() {
+    // Set up a server that processes 20 messages per second per connection
+    let server = Arc::new(Server::with_rate_limit("localhost:0", 20).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Send two seconds' worth of pings at once
+    let started = Instant::now();
+    for nonce in 0..40 {
+        let message = client_message::Message::PingRequest(PingRequest { nonce });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+    }
+
+    // All are answered, but only the first second's worth without delay
+    for nonce in 0..40 {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::PongResponse(pong)) => assert_eq!(pong.nonce, nonce),
+            _ => panic!("Expected PongResponse, but received a different message"),
+        }
+    }
+    assert!(
+        started.elapsed() >= Duration::from_millis(900),
+        "40 messages at 20 per second took only {:?}",
+        started.elapsed()
+    );
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stats_totals_count_traffic_across_clients() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect both clients
+    let mut first = client::Client::new("localhost", port, 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    let mut second = client::Client::new("localhost", port, 1000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+
+    // Three echoes from one client and two from the other
+    let mut bytes = 0;
+    for (client, count) in [(&mut first, 3), (&mut second, 2)] {
+        for i in 0..count {
+            let message = ClientMessage {
+                message: Some(client_message::Message::EchoMessage(EchoMessage {
+                    content: format!("echo {}", i),
+                })),
+                ..Default::default()
+            };
+            bytes += 4 + message.encoded_len() as u64;
+            assert!(client.send_message(message).is_ok(), "Failed to send message");
+            assert!(client.receive().is_ok(), "Failed to receive response");
+        }
+    }
+
+    // The last reply can reach the client before its bytes are counted
+    assert!(
+        wait_for(Duration::from_secs(1), || server.stats().bytes_written == bytes),
+        "Written bytes never settled"
+    );
+    let stats = server.stats();
+    assert_eq!(stats.connections_accepted, 2);
+    assert_eq!(stats.connected_clients, 2);
+    assert_eq!(stats.messages_processed, 5);
+    assert_eq!(stats.bytes_read, bytes);
+    assert_eq!(stats.bytes_written, bytes, "Echoes encode to the same size as their requests");
+
+    // Disconnect the clients
+    for client in [&mut first, &mut second] {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Totals outlive the connections that produced them
+    assert!(
+        wait_for(Duration::from_secs(1), || server.stats().connected_clients == 0),
+        "Clients are still counted as connected"
+    );
+    let after = server.stats();
+    assert_eq!(
+        (after.connections_accepted, after.messages_processed, after.bytes_read, after.bytes_written),
+        (2, 5, bytes, bytes)
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_connections_lists_live_peers() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Connect two clients; both peer addresses are listed
+    let mut first = client::Client::new("localhost", port, 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    let mut second = client::Client::new("localhost", port, 1000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    let first_addr = first.local_addr().expect("Failed to read client address");
+    let second_addr = second.local_addr().expect("Failed to read client address");
+    assert!(
+        wait_for(Duration::from_secs(1), || server.connections().len() == 2),
+        "Expected both connections to be listed"
+    );
+    let peers: Vec<_> = server.connections().into_iter().map(|(_, addr)| addr).collect();
+    assert!(peers.contains(&first_addr), "{} missing from {:?}", first_addr, peers);
+    assert!(peers.contains(&second_addr), "{} missing from {:?}", second_addr, peers);
+
+    // Once one leaves, only the other is listed
+    assert!(first.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(
+        wait_for(Duration::from_secs(1), || server.connections().len() == 1),
+        "The closed connection is still listed"
+    );
+    assert_eq!(server.connections()[0].1, second_addr);
+
+    // Disconnect the client
+    assert!(
+        second.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_connection_metrics_snapshot() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Connect two clients
+    let mut pinger = client::Client::new("localhost", port, 1000);
+    assert!(pinger.connect().is_ok(), "Failed to connect to the server");
+    let mut echoer = client::Client::new("localhost", port, 1000);
+    assert!(echoer.connect().is_ok(), "Failed to connect to the server");
+
+    // One sends three pings, the other a single larger echo; count the bytes each sends
+    let mut pinger_bytes = 0;
+    for nonce in 0..3 {
+        let message = ClientMessage {
+            message: Some(client_message::Message::PingRequest(PingRequest { nonce })),
+            ..Default::default()
+        };
+        pinger_bytes += 4 + message.encoded_len() as u64;
+        assert!(pinger.send_message(message).is_ok(), "Failed to send message");
+        assert!(pinger.receive().is_ok(), "Failed to receive response");
+    }
+    let message = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "metrics".repeat(20),
+        })),
+        ..Default::default()
+    };
+    let echoer_bytes = 4 + message.encoded_len() as u64;
+    assert!(echoer.send_message(message).is_ok(), "Failed to send message");
+    assert!(echoer.receive().is_ok(), "Failed to receive response");
+
+    // Each connection reports its own traffic, once the last reply is counted
+    assert!(
+        wait_for(Duration::from_secs(1), || {
+            let written: u64 = server
+                .connection_metrics_snapshot()
+                .iter()
+                .map(|metrics| metrics.bytes_out)
+                .sum();
+            written == pinger_bytes + echoer_bytes
+        }),
+        "Outgoing bytes never settled"
+    );
+    let snapshot = server.connection_metrics_snapshot();
+    assert_eq!(snapshot.len(), 2, "Expected one entry per connection: {:?}", snapshot);
+    let find = |client: &client::Client| {
+        let addr = client.local_addr().expect("Failed to read client address");
+        snapshot
+            .iter()
+            .find(|metrics| metrics.peer_addr == addr)
+            .expect("Connection missing from snapshot")
+            .clone()
+    };
+    let (pinger_metrics, echoer_metrics) = (find(&pinger), find(&echoer));
+    assert_ne!(pinger_metrics.id, echoer_metrics.id);
+    assert_eq!(pinger_metrics.messages, 3);
+    assert_eq!(echoer_metrics.messages, 1);
+    assert_eq!(pinger_metrics.bytes_in, pinger_bytes);
+    assert_eq!(echoer_metrics.bytes_in, echoer_bytes);
+    // Pongs and echoes encode to the same size as the requests they answer
+    assert_eq!(pinger_metrics.bytes_out, pinger_bytes);
+    assert_eq!(echoer_metrics.bytes_out, echoer_bytes);
+    assert_eq!(pinger_metrics.queue_depth, 0);
+    assert!(pinger_metrics.idle <= pinger_metrics.connected_for);
+
+    // Disconnect the clients
+    for client in [&mut pinger, &mut echoer] {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_memory_pressure_refuses_connections_until_it_eases() {
+    // Set up a server that allows 4 KB of unprocessed bytes across all clients
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .max_buffered_bytes(4096)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+    let port = server_port(&server);
+
+    // An idle client that holds nothing, connected first so it is the most idle
+    let mut bystander = client::Client::new("localhost", port, 1000);
+    assert!(bystander.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut bystander, 1);
+
+    // One client sends most of a 10 KB frame and stops, leaving it buffered
+    let mut hoarder = TcpStream::connect(addr).expect("Failed to connect to the server");
+    hoarder
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+    hoarder.write_all(&10_000u32.to_be_bytes()).expect("Failed to send header");
+    hoarder.write_all(&[0u8; 5000]).expect("Failed to send partial payload");
+    assert!(
+        wait_for(Duration::from_secs(1), || {
+            server.connection_metrics_snapshot().iter().map(|metrics| metrics.queue_depth).sum::<usize>() >= 4096
+        }),
+        "Server never buffered the partial frame"
+    );
+
+    // A new connection is refused, and the hoarder rather than the idle bystander is shed to make room
+    let mut refused = client::Client::new("localhost", port, 1000);
+    assert!(refused.connect().is_ok(), "Failed to connect to the server");
+    assert_server_full(&mut refused);
+    let mut buffer = [0u8; 16];
+    match hoarder.read(&mut buffer) {
+        Ok(bytes_read) => assert_eq!(bytes_read, 0, "Shed client should just be closed"),
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset, "Unexpected error: {}", e),
+    }
+
+    // Once its buffer is released, connections are accepted again, and the bystander was never touched
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 1),
+        "Shed client was not released"
+    );
+    assert_ping(&mut bystander, 2);
+    assert!(
+        bystander.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut client, 4);
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stop_interrupts_idle_client() {
+    // Set up a server whose client reads would otherwise block for a minute
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .read_timeout(Duration::from_secs(60))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Connect a client and leave it idle
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 1),
+        "Client was never accepted"
+    );
+
+    // Stopping must not wait for the blocked read to time out
+    let start = Instant::now();
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "Stopping with an idle client took {:?}",
+        start.elapsed()
+    );
+
+    // The client sees the server close the connection
+    assert!(client.receive().is_err(), "Server should have closed the connection");
+}
+
+#[test]
+fn test_progress_messages_sent_at_interval() {
+    // Set up a server that reports progress every 10 frames
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .progress_interval(10)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Stream 25 frames without waiting for replies
+    let num_messages = 25;
+    for i in 0..num_messages {
+        let echo_message = EchoMessage {
+            content: format!("Frame {}", i),
+        };
+        assert!(
+            client.send(client_message::Message::EchoMessage(echo_message)).is_ok(),
+            "Failed to send message {}",
+            i
+        );
+    }
+
+    // Progress follows the 10th and 20th echo, interleaved with the responses
+    let mut echoes = 0;
+    let mut progress = Vec::new();
+    while echoes < num_messages {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("Frame {}", echoes), "Echoed message content does not match");
+                echoes += 1;
+            }
+            Some(server_message::Message::ProgressMessage(message)) => {
+                assert_eq!(
+                    message.frames_received, echoes as u64,
+                    "Progress should arrive right after the frame it counts"
+                );
+                progress.push(message.frames_received);
+            }
+            _ => panic!("Expected EchoMessage or ProgressMessage, but received a different message"),
+        }
+    }
+    assert_eq!(progress, vec![10, 20], "Progress messages did not arrive at the configured interval");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_count_tracks_connected_clients() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    assert_eq!(server.client_count(), 0, "No clients should be connected yet");
+
+    // Create and connect three clients
+    let mut clients = [
+        client::Client::new("localhost", port, 1000),
+        client::Client::new("localhost", port, 1000),
+        client::Client::new("localhost", port, 1000),
+    ];
+    for client in clients.iter_mut() {
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+    }
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 3),
+        "Expected 3 connected clients, found {}",
+        server.client_count()
+    );
+
+    // Disconnecting one client drops the count after a short settle
+    assert!(
+        clients[0].disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 2),
+        "Expected 2 connected clients, found {}",
+        server.client_count()
+    );
+
+    // Disconnect the remaining clients
+    for client in clients.iter_mut().skip(1) {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_finished_client_threads_are_pruned() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Open and close 50 short-lived connections one after another
+    for i in 0..50 {
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect client {}", i);
+
+        let echo_message = EchoMessage {
+            content: format!("Short lived {}", i),
+        };
+        assert!(
+            client.send(client_message::Message::EchoMessage(echo_message)).is_ok(),
+            "Failed to send message {}",
+            i
+        );
+        assert!(client.receive().is_ok(), "Failed to receive response {}", i);
+        assert!(client.disconnect().is_ok(), "Failed to disconnect client {}", i);
+
+        // Let the thread exit so the next accept can prune it
+        assert!(
+            wait_for(Duration::from_secs(1), || server.client_count() == 0),
+            "Client {} did not finish",
+            i
+        );
+    }
+
+    // Only a handful of recent handles may still be around, not one per connection ever made
+    assert!(
+        server.tracked_client_threads() < 5,
+        "Expected finished client threads to be pruned, {} handles retained",
+        server.tracked_client_threads()
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// StatsCalcResponse as an older client knew it, before stddev and count were added
+#[derive(Clone, PartialEq, prost::Message)]
+struct LegacyStatsCalcResponse {
+    #[prost(double, tag = "1")]
+    mean: f64,
+}
+
+// ServerMessage as an older client knew it, with only the stats response
+#[derive(Clone, PartialEq, prost::Message)]
+struct LegacyServerMessage {
+    #[prost(oneof = "legacy_server_message::Message", tags = "3")]
+    message: Option<legacy_server_message::Message>,
+}
+
+mod legacy_server_message {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Message {
+        #[prost(message, tag = "3")]
+        StatsCalcResponse(super::LegacyStatsCalcResponse),
+    }
+}
+
+#[test]
+fn test_new_response_fields_decode_with_older_definitions() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Talk to the server over a raw socket so the response bytes can be decoded by hand
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set read timeout");
+
+    let request = ClientMessage {
+        message: Some(client_message::Message::StatsCalcRequest(StatsCalcRequest {
+            values: vec![1.0, 2.0, 3.0],
+        })),
+        ..Default::default()
+    };
+    let payload = request.encode_to_vec();
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .expect("Failed to send header");
+    stream.write_all(&payload).expect("Failed to send payload");
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).expect("Failed to read header");
+    let mut response = vec![0u8; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut response).expect("Failed to read payload");
+
+    // Stop the server and wait for thread to finish
+    drop(stream);
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // An older client skips the fields it doesn't know and still gets the core one
+    let legacy = LegacyServerMessage::decode(response.as_slice())
+        .expect("Older definition failed to decode the response");
+    match legacy.message {
+        Some(legacy_server_message::Message::StatsCalcResponse(stats)) => {
+            assert_eq!(stats.mean, 2.0);
+        }
+        _ => panic!("Expected StatsCalcResponse, but received a different message"),
+    }
+
+    // A current client likewise ignores fields added after it was built
+    let mut future = response.clone();
+    future.extend_from_slice(&[0xc8, 0x01, 0x2a]); // Unknown varint field 25 = 42
+    future.extend_from_slice(&[0xd2, 0x01, 0x03, b'n', b'e', b'w']); // Unknown string field 26 = "new"
+    let current = ServerMessage::decode(future.as_slice())
+        .expect("Current definition failed to decode a response with unknown fields");
+    match current.message {
+        Some(server_message::Message::StatsCalcResponse(stats)) => {
+            assert_eq!(stats.mean, 2.0);
+            assert_eq!(stats.count, 3);
+        }
+        _ => panic!("Expected StatsCalcResponse, but received a different message"),
+    }
+}
+
+// connect clients_per_thread clients one after another from each of client_threads threads, each echoing once
+fn run_echo_sessions(port: u32, client_threads: usize, clients_per_thread: usize) {
+    let sessions: Vec<_> = (0..client_threads)
+        .map(|_| {
+            thread::spawn(move || {
+                for _ in 0..clients_per_thread {
+                    let mut client = client::Client::new("localhost", port, 1000);
+                    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+                    let message = client_message::Message::EchoMessage(EchoMessage {
+                        content: "Pooled".to_string(),
+                    });
+                    assert!(client.send(message).is_ok(), "Failed to send message");
+                    match client.receive().expect("Failed to receive response").message {
+                        Some(server_message::Message::EchoMessage(echo)) => {
+                            assert_eq!(echo.content, "Pooled");
+                        }
+                        _ => panic!("Expected EchoMessage, but received a different message"),
+                    }
+
+                    assert!(
+                        client.disconnect().is_ok(),
+                        "Failed to disconnect from the server"
+                    );
+                }
+            })
+        })
+        .collect();
+    for session in sessions {
+        assert!(session.join().is_ok(), "Client thread panicked");
+    }
+}
+
+#[test]
+fn test_worker_pool_uses_bounded_threads() {
+    const WORKERS: usize = 4;
+
+    // Baseline: the thread-per-client server
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let start = Instant::now();
+    run_echo_sessions(server_port(&server), 20, 10);
+    let per_client_elapsed = start.elapsed();
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Same 200 clients against a fixed pool of workers
+    let server = Arc::new(Server::with_workers("localhost:0", WORKERS).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let start = Instant::now();
+    run_echo_sessions(server_port(&server), 20, 10);
+    let pool_elapsed = start.elapsed();
+
+    println!(
+        "200 clients: thread per client {:?}, {} workers {:?}",
+        per_client_elapsed, WORKERS, pool_elapsed
+    );
+    assert_eq!(
+        server.tracked_client_threads(),
+        WORKERS,
+        "The pool should never run more threads than its workers"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert_eq!(server.tracked_client_threads(), 0, "Workers should be joined on stop");
+}
+
+#[test]
+fn test_worker_pool_rejects_zero_workers() {
+    let result = Server::with_workers("localhost:0", 0);
+    assert!(result.is_err(), "A pool with no workers could never serve a client");
+}
+
+#[test]
+fn test_read_ahead_limit_bounds_buffered_bytes() {
+    const READ_AHEAD_LIMIT: usize = 64;
+
+    // Set up a server that buffers at most 64 unprocessed bytes per client
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .read_ahead_limit(READ_AHEAD_LIMIT)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect a single client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Pipeline far more than the limit before reading any reply, ending with a frame larger than the limit
+    let num_messages = 500;
+    let large_content = "L".repeat(1024);
+    for i in 0..num_messages {
+        let echo_message = EchoMessage {
+            content: format!("Pipelined Message {}", i),
+        };
+        let message = client_message::Message::EchoMessage(echo_message);
+
+        assert!(client.send(message).is_ok(), "Failed to send message {}", i);
+    }
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: large_content.clone(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send large message");
+
+    // Every echo must still come back intact and in order
+    for i in 0..num_messages {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("Pipelined Message {}", i));
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+    match client.receive().expect("Failed to receive large response").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, large_content);
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Only the large frame itself (4-byte header, message tags and content) may exceed the limit
+    let largest_frame = 4 + 1024 + 16;
+    assert!(
+        server.peak_read_ahead() <= largest_frame,
+        "Buffered {} bytes, more than the largest frame",
+        server.peak_read_ahead()
+    );
+    assert!(server.peak_read_ahead() > READ_AHEAD_LIMIT, "The large frame should have been buffered whole");
+}
+
+#[test]
+fn test_quiesce_single_connection() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Connect several clients
+    let mut clients: Vec<client::Client> = (0..3)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            client
+        })
+        .collect();
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 3),
+        "Server should see all three clients"
+    );
+
+    // Recycle the middle connection only
+    let target = clients[1].local_addr().expect("Failed to read client address");
+    assert!(server.quiesce_client(target), "Server should find the targeted connection");
+
+    match clients[1].receive().expect("Targeted client got no message").message {
+        Some(server_message::Message::PleaseReconnect(_)) => {}
+        _ => panic!("Expected PleaseReconnect, but received a different message"),
+    }
+    let closed = clients[1].receive();
+    assert!(closed.is_err(), "Targeted connection should be closed after PleaseReconnect");
+
+    // The other connections keep working
+    for i in [0, 2] {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("Still here {}", i),
+        });
+        assert!(clients[i].send(message).is_ok(), "Failed to send message");
+        match clients[i].receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("Still here {}", i));
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 2),
+        "Only the targeted client should be gone"
+    );
+
+    // Disconnect the remaining clients
+    for i in [0, 2] {
+        assert!(
+            clients[i].disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_idle_connection_is_closed() {
+    let idle_timeout = Duration::from_millis(300);
+
+    // Set up a server that drops clients after 300ms of silence
+    let server = Arc::new(Server::with_idle_timeout("localhost:0", idle_timeout).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Connect and send nothing
+    let connected = Instant::now();
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+
+    // The server should close the stream once the idle window passes
+    let mut buffer = [0u8; 16];
+    let bytes_read = stream.read(&mut buffer).expect("Server did not close the idle connection");
+    assert_eq!(bytes_read, 0, "Server should close without sending anything");
+    assert!(
+        connected.elapsed() >= idle_timeout,
+        "Connection closed before the idle timeout: {:?}",
+        connected.elapsed()
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_slow_partial_frame_is_closed() {
+    let frame_timeout = Duration::from_millis(300);
+
+    // Set up a server that gives each frame 300ms to arrive in full
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .frame_timeout(frame_timeout)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Declare a 100 byte payload, then drip it one byte every 50ms
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("Failed to set read timeout");
+    stream.write_all(&100u32.to_be_bytes()).expect("Failed to send header");
+    let started = Instant::now();
+    let mut writer = stream.try_clone().expect("Failed to clone stream");
+    let dripper = thread::spawn(move || {
+        for _ in 0..100 {
+            if writer.write_all(&[0]).is_err() {
+                return; // Server hung up
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+
+    // The server closes the connection even though bytes keep arriving
+    let mut buffer = [0u8; 16];
+    match stream.read(&mut buffer) {
+        Ok(bytes_read) => assert_eq!(bytes_read, 0, "Server should close without sending anything"),
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset, "Unexpected error: {}", e),
+    }
+    assert!(
+        started.elapsed() >= frame_timeout && started.elapsed() < Duration::from_secs(2),
+        "Connection closed after {:?}, expected shortly after the frame timeout",
+        started.elapsed()
+    );
+    let _ = stream.shutdown(Shutdown::Both); // Stop the dripper if it hasn't noticed yet
+    assert!(dripper.join().is_ok(), "Dripping thread panicked");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_short_poll_interval_still_serves_and_drains() {
+    // Set up a server that polls every 1ms and stops by itself after one connection
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .poll_interval(Duration::from_millis(1))
+            .max_lifetime_connections(1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Requests are answered as usual
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "fast poll".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive echo").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "fast poll"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Draining polls at the shorter interval, and still notices the client leaving
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    assert!(
+        wait_for(Duration::from_secs(2), || handle.is_finished()),
+        "Server did not stop after draining"
+    );
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // A zero interval would spin, so it is refused
+    assert!(matches!(
+        Server::builder().addr("localhost:0").poll_interval(Duration::ZERO).build(),
+        Err(ServerError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn test_builder_applies_several_options() {
+    // Set up a server with a large buffer, an idle timeout and room for two clients
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .buffer_size(8192)
+            .idle_timeout(Duration::from_millis(300))
+            .max_connections(2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Two clients are served, and a large echo fits the buffer
+    let mut clients: Vec<client::Client> = (0..2)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            client
+        })
+        .collect();
+    let content = "x".repeat(6000);
+    let message = client_message::Message::EchoMessage(EchoMessage { content: content.clone() });
+    assert!(clients[0].send(message).is_ok(), "Failed to send message");
+    match clients[0].receive().expect("Failed to receive echo").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 2),
+        "Server should see both clients"
+    );
+
+    // A third is over max_connections and is turned away with SERVER_FULL
+    let mut third = client::Client::new("localhost", port, 1000);
+    assert!(third.connect().is_ok(), "Failed to connect to the server");
+    assert_server_full(&mut third);
+    assert!(
+        wait_for(Duration::from_secs(1), || server.stats().rejected_connections == 1),
+        "Third connection should be counted as rejected"
+    );
+
+    // Silent clients are dropped after the idle timeout
+    assert!(
+        wait_for(Duration::from_secs(2), || server.client_count() == 0),
+        "Idle clients were not closed"
+    );
+    for client in clients.iter_mut() {
+        let _ = client.disconnect(); // Already closed by the server
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_self_test_probe_bypasses_allowlist_and_callbacks() {
+    // Set up a self-testing server whose allowlist excludes loopback, where the probe comes from
+    let connected = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let disconnected = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut server = Server::builder()
+        .addr("127.0.0.1:0")
+        .allowlist(vec!["10.0.0.1".parse().unwrap()])
+        .self_test(true)
+        .build()
+        .expect("Failed to start server");
+    {
+        let connected = connected.clone();
+        server.on_connect(move |addr| connected.lock().unwrap().push(addr));
+        let disconnected = disconnected.clone();
+        server.on_disconnect(move |addr| disconnected.lock().unwrap().push(addr));
+    }
+    let server = Arc::new(server);
+    let handle = server.clone().start_in_background();
+
+    // The self-test still passes, and the probe shows up nowhere
+    assert!(server.is_running(), "Self-test should pass despite the allowlist");
+    assert_eq!(server.client_count(), 0, "The probe should not count as a connected client");
+    assert!(server.connection_metrics_snapshot().is_empty(), "The probe should not be listed");
+    let stats = server.stats();
+    assert_eq!(stats.connections_accepted, 0, "The probe should not count as an accepted connection");
+    assert_eq!(stats.messages_processed, 0, "The probe's ping should not count as processed");
+    assert_eq!(stats.bytes_read, 0, "The probe's ping should not count as read");
+    assert_eq!(stats.bytes_written, 0, "The probe's pong should not count as written");
+    assert!(connected.lock().unwrap().is_empty(), "on_connect ran for the probe");
+    assert!(disconnected.lock().unwrap().is_empty(), "on_disconnect ran for the probe");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        matches!(handle.join(), Ok(Ok(()))),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_failed_self_test_never_reports_ready() {
+    // Set up a self-testing server that can't answer the probe's ping, which is larger than max_message_size
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .self_test(true)
+            .max_message_size(1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = server.clone().run_in_background();
+
+    // The server is never reported ready, not even while the self-test runs, and run gives up with its error
+    let mut seen_ready = false;
+    while !handle.is_finished() {
+        seen_ready |= server.wait_until_ready(Duration::ZERO);
+    }
+    assert!(!seen_ready, "A server that failed its self-test was reported ready");
+    assert!(
+        matches!(handle.join(), Ok(Err(ServerError::SelfTest(_)))),
+        "Expected run to fail its self-test"
+    );
+}
+
+// Custom handler that shouts every echo back and leaves everything else to the default behaviour
+struct UppercaseEchoHandler;
+
+impl MessageHandler for UppercaseEchoHandler {
+    fn handle(&self, msg: ClientMessage) -> Option<ServerMessage> {
+        match msg.message {
+            Some(client_message::Message::EchoMessage(echo)) => Some(ServerMessage {
+                message: Some(server_message::Message::EchoMessage(EchoMessage {
+                    content: echo.content.to_uppercase(),
+                })),
+                ..Default::default()
+            }),
+            _ => DefaultHandler.handle(msg),
+        }
+    }
+}
+
+#[test]
+fn test_custom_message_handler() {
+    // Set up a server with the custom handler
+    let server = Arc::new(
+        Server::with_handler("localhost:0", Box::new(UppercaseEchoHandler)).expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Echoes come back transformed by the handler
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "Hello, handler!".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "HELLO, HANDLER!", "Echo was not transformed by the handler");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Requests the handler passes on keep their default behaviour
+    let message = client_message::Message::AddRequest(AddRequest { a: 2, b: 3 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::AddResponse(add_response)) => {
+            assert_eq!(add_response.result, 5, "AddResponse result does not match");
+        }
+        _ => panic!("Expected AddResponse, but received a different message"),
+    }
+
+    // So does RangeRequest, though the handler only implements handle
+    let message = client_message::Message::RangeRequest(RangeRequest { start: 1, end: 2 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    for expected in ["1", "2"] {
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, expected),
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Custom handler that never answers anything
+struct SilentHandler;
+
+impl MessageHandler for SilentHandler {
+    fn handle(&self, _msg: ClientMessage) -> Option<ServerMessage> {
+        None
+    }
+}
+
+#[test]
+fn test_unanswered_request_gets_error_reply() {
+    // Set up a server whose handler has nothing to say
+    let server = Arc::new(Server::with_handler("localhost:0", Box::new(SilentHandler)).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let mut client = client::Client::builder("localhost", server_port(&server))
+        .read_timeout(Duration::from_secs(1))
+        .build();
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // The client gets an error instead of waiting for a reply that never comes
+    let message = client_message::Message::AddRequest(AddRequest { a: 1, b: 2 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::Unspecified);
+            assert!(error.message.contains("AddRequest"), "Unexpected message: {}", error.message);
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_broadcast_reaches_other_clients() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Connect three clients
+    let mut clients: Vec<client::Client> = (0..3)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            client
+        })
+        .collect();
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 3),
+        "Server should see all three clients"
+    );
+
+    // Broadcast from the first client
+    let message = client_message::Message::BroadcastMessage(BroadcastMessage {
+        content: "Hello, everyone!".to_string(),
+    });
+    assert!(clients[0].send(message).is_ok(), "Failed to send broadcast");
+
+    // Both other clients receive it
+    for client in clients.iter_mut().skip(1) {
+        match client.receive().expect("Failed to receive broadcast").message {
+            Some(server_message::Message::BroadcastMessage(broadcast)) => {
+                assert_eq!(broadcast.content, "Hello, everyone!");
+            }
+            _ => panic!("Expected BroadcastMessage, but received a different message"),
+        }
+    }
+
+    // The sender does not get its own broadcast back: the next thing it receives is its pong
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 7 });
+    assert!(clients[0].send(message).is_ok(), "Failed to send message");
+    match clients[0].receive().expect("Failed to receive response").message {
+        Some(server_message::Message::PongResponse(pong)) => {
+            assert_eq!(pong.nonce, 7);
+        }
+        _ => panic!("Expected PongResponse, but received a different message"),
+    }
+
+    // Disconnect the clients
+    for client in clients.iter_mut() {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_broadcast_encoded_once_for_all_recipients() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Connect a sender and five recipients
+    let mut clients: Vec<client::Client> = (0..6)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            client
+        })
+        .collect();
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 6),
+        "Server should see all six clients"
+    );
+
+    // Broadcast once from the first client
+    let message = client_message::Message::BroadcastMessage(BroadcastMessage {
+        content: "Shared payload".to_string(),
+    });
+    assert!(clients[0].send(message).is_ok(), "Failed to send broadcast");
+
+    // Every recipient gets it, from a single encode
+    for client in clients.iter_mut().skip(1) {
+        match client.receive().expect("Failed to receive broadcast").message {
+            Some(server_message::Message::BroadcastMessage(broadcast)) => {
+                assert_eq!(broadcast.content, "Shared payload");
+            }
+            _ => panic!("Expected BroadcastMessage, but received a different message"),
+        }
+    }
+    assert_eq!(server.stats().broadcasts_encoded, 1, "Broadcast should be encoded once for all recipients");
+
+    // Disconnect the clients
+    for client in clients.iter_mut() {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_timing_breakdown_on_request() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Ask for a timing breakdown on a trivial echo
+    let message = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "Timed".to_string(),
+        })),
+        include_timing: true,
+        ..Default::default()
+    };
+    assert!(client.send_message(message).is_ok(), "Failed to send message");
+
+    let response = client.receive().expect("Failed to receive response");
+    match response.message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "Timed");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    let timing = response.timing.expect("Response is missing the timing breakdown");
+    assert!(
+        timing.handler_ns < Duration::from_millis(50).as_nanos() as u64,
+        "Handling an echo took {}ns",
+        timing.handler_ns
+    );
+    assert!(
+        timing.decode_ns > 0 || timing.encode_ns > 0,
+        "Breakdown should carry real measurements: {:?}",
+        timing
+    );
+
+    // Without the option the breakdown is left out
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "Untimed".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    let response = client.receive().expect("Failed to receive response");
+    assert!(response.timing.is_none(), "Timing should only be sent when asked for");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_responses_carry_request_id() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Pipeline three requests before reading any response
+    let requests = [
+        client_message::Message::PingRequest(PingRequest { nonce: 7 }),
+        client_message::Message::EchoMessage(EchoMessage {
+            content: "Correlated".to_string(),
+        }),
+        client_message::Message::AddRequest(AddRequest { a: 2, b: 3 }),
+    ];
+    for (request_id, request) in (1..).zip(requests) {
+        let message = ClientMessage {
+            message: Some(request),
+            request_id,
+            ..Default::default()
+        };
+        assert!(client.send_message(message).is_ok(), "Failed to send message");
+    }
+
+    // Each response names the request it answers
+    for request_id in 1..=3 {
+        let response = client.receive().expect("Failed to receive response");
+        assert_eq!(response.request_id, request_id, "Unexpected response: {:?}", response);
+    }
+
+    // Requests without an id get responses without one
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 8 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    let response = client.receive().expect("Failed to receive response");
+    assert_eq!(response.request_id, 0);
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// the server turned this connection away with SERVER_FULL and closed it
+fn assert_server_full(client: &mut client::Client) {
+    match client.receive().expect("Expected a SERVER_FULL error").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code(), ErrorCode::ServerFull, "Expected a SERVER_FULL error");
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+    assert!(client.receive().is_err(), "Connection should be closed after SERVER_FULL");
+}
+
+#[test]
+fn test_saturation_policy_reject() {
+    let (server, handle, port, mut clients) = start_saturated_server(SaturationPolicy::Reject);
+
+    // The next connection is told the server is full and closed
+    let mut extra = client::Client::new("localhost", port, 1000);
+    assert!(extra.connect().is_ok(), "Failed to connect to the server");
+    assert_server_full(&mut extra);
+    assert_eq!(server.stats().rejected_connections, 1);
+
+    // Disconnect the clients
+    for client in clients.iter_mut() {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_saturation_policy_burst() {
+    let (server, handle, port, mut clients) = start_saturated_server(SaturationPolicy::Burst(1));
+
+    // One more connection is served straight away on a burst thread
+    let mut burst = client::Client::new("localhost", port, 1000);
+    assert!(burst.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut burst, 3);
+    assert_eq!(server.stats().burst_clients, 1);
+
+    // Past the burst cap connections are rejected
+    let mut extra = client::Client::new("localhost", port, 1000);
+    assert!(extra.connect().is_ok(), "Failed to connect to the server");
+    assert_server_full(&mut extra);
+
+    // The burst thread is given back when its client leaves
+    assert!(
+        burst.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    assert!(
+        wait_for(Duration::from_secs(1), || server.stats().burst_clients == 0),
+        "Burst thread was not released"
+    );
+
+    // Disconnect the clients
+    for client in clients.iter_mut() {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_worker_queue_stats_and_runtime_cap() {
+    // Set up a server with a single worker, so every other connection has to queue
+    let server = Arc::new(Server::with_workers("localhost:0", 1).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // The first client occupies the only worker
+    let mut busy = client::Client::new("localhost", port, 1000);
+    assert!(busy.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 1 });
+    assert!(busy.send(message).is_ok(), "Failed to send message");
+    assert!(busy.receive().is_ok(), "Failed to receive response");
+
+    // Three more pile up in the accept queue
+    let mut waiting: Vec<client::Client> = (0..3)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            client
+        })
+        .collect();
+    assert!(
+        wait_for(Duration::from_secs(1), || server.stats().queued_connections == 3),
+        "Expected three queued connections, got {:?}",
+        server.stats()
+    );
+    assert!(server.stats().queue_high_water >= 3, "High-water mark should record the backlog");
+
+    // Shrinking the cap at runtime turns the next connection away
+    server.set_max_queued_connections(Some(1));
+    let mut rejected = client::Client::new("localhost", port, 1000);
+    assert!(rejected.connect().is_ok(), "Failed to connect to the server");
+    assert_server_full(&mut rejected);
+    assert_eq!(server.stats().rejected_connections, 1);
+    assert_eq!(server.stats().queued_connections, 3, "Queued connections are kept when the cap shrinks");
+
+    // Disconnect everyone; the queue drains through the worker
+    assert!(busy.disconnect().is_ok(), "Failed to disconnect from the server");
+    for client in waiting.iter_mut() {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+    assert!(
+        wait_for(Duration::from_secs(2), || server.stats().queued_connections == 0),
+        "Queue should drain once the worker is free"
+    );
+    assert!(server.stats().queue_high_water > 0, "High-water mark is kept after the queue drains");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_server_errors_are_typed() {
+    // Binding to an address that is already taken is a Bind error
+    let server = create_server();
+    let taken = server.local_addr().expect("Failed to read server address").to_string();
+    match Server::new(&taken) {
+        Err(ServerError::Bind(e)) => {
+            assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+        }
+        Err(e) => panic!("Expected a Bind error, got {}", e),
+        Ok(_) => panic!("Binding a taken address should fail"),
+    }
+
+    // A setting the server can't work with is rejected before binding
+    match Server::with_buffer_size("localhost:0", 0) {
+        Err(ServerError::InvalidConfig(reason)) => {
+            assert!(reason.contains("Buffer size"), "Unexpected reason: {}", reason);
+        }
+        Err(e) => panic!("Expected an InvalidConfig error, got {}", e),
+        Ok(_) => panic!("A zero buffer size should be rejected"),
+    }
+}
+
+// start a paced stream echo and return once the first echo has arrived, so the request is in flight
+fn start_stream_echo(client: &mut client::Client, count: u32, interval_ms: u32) {
+    let message = client_message::Message::StreamEchoRequest(StreamEchoRequest {
+        content: "Draining".to_string(),
+        count,
+        interval_ms,
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive first streamed echo");
+}
+
+#[test]
+fn test_operation_limit_leaves_echoes_responsive() {
+    // Set up a server that runs one StreamEchoRequest at a time
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .operation_limit("StreamEchoRequest", 1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // The first client takes the only slot with a slow stream
+    let mut streaming = client::Client::new("localhost", port, 1000);
+    assert!(streaming.connect().is_ok(), "Failed to connect to the server");
+    start_stream_echo(&mut streaming, 5, 100);
+
+    // A second stream is turned away while the slot is held
+    let mut blocked = client::Client::new("localhost", port, 1000);
+    assert!(blocked.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::StreamEchoRequest(StreamEchoRequest {
+        content: "Blocked".to_string(),
+        count: 1,
+        interval_ms: 0,
+    });
+    assert!(blocked.send(message).is_ok(), "Failed to send message");
+    match blocked.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.code, i32::from(ErrorCode::Overloaded), "Expected an OVERLOADED error");
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+
+    // Echoes have no limit and are answered straight away
+    let mut echoing = client::Client::new("localhost", port, 1000);
+    assert!(echoing.connect().is_ok(), "Failed to connect to the server");
+    let started = Instant::now();
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "Still fast".to_string(),
+    });
+    assert!(echoing.send(message).is_ok(), "Failed to send message");
+    assert!(echoing.receive().is_ok(), "Failed to receive echo");
+    assert!(
+        started.elapsed() < Duration::from_millis(100),
+        "Echo took {:?} while the stream limit was saturated",
+        started.elapsed()
+    );
+
+    // Let the stream finish, then disconnect the clients
+    for _ in 1..5 {
+        assert!(streaming.receive().is_ok(), "Failed to receive streamed echo");
+    }
+    for client in [&mut streaming, &mut blocked, &mut echoing] {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_operation_limit_rejects_unknown_request_type() {
+    // A misspelt request type would otherwise be silently ignored
+    match Server::builder()
+        .addr("localhost:0")
+        .operation_limit("StreamEchoRequets", 1)
+        .build()
+    {
+        Err(ServerError::InvalidConfig(reason)) => {
+            assert!(reason.contains("known request type"), "Unexpected reason: {}", reason);
+        }
+        Err(e) => panic!("Expected an InvalidConfig error, got {}", e),
+        Ok(_) => panic!("An unknown request type should be rejected"),
+    }
+
+    // Every type the access log names can be limited
+    assert!(
+        Server::builder()
+            .addr("localhost:0")
+            .operation_limit("ServerInfoRequest", 1)
+            .operation_limit("Empty", 1)
+            .build()
+            .is_ok(),
+        "Known request types should be accepted"
+    );
+}
+
+#[test]
+fn test_stop_with_timeout_drains_or_force_closes() {
+    // A slow request that finishes inside the drain window
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    start_stream_echo(&mut client, 4, 100);
+
+    let stopper = {
+        let server = server.clone();
+        thread::spawn(move || server.stop_with_timeout(Duration::from_secs(2)))
+    };
+
+    // The rest of the stream still arrives before the server closes the connection
+    for i in 1..4 {
+        match client.receive().expect("In-flight request was cut off").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, "Draining", "Streamed echo {} does not match", i);
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+    assert!(client.receive().is_err(), "Connection should close once the request is done");
+    assert!(stopper.join().expect("Stop thread panicked"), "Slow client should drain cleanly");
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // A request far longer than the drain window gets force-closed
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    start_stream_echo(&mut client, 100, 100);
+
+    let started = Instant::now();
+    assert!(
+        !server.stop_with_timeout(Duration::from_millis(300)),
+        "Stuck client should have been force-closed"
+    );
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "Force close took {:?}",
+        started.elapsed()
+    );
+
+    let mut echoes = 1;
+    while client.receive().is_ok() {
+        echoes += 1;
+    }
+    assert!(echoes < 100, "Stream should have been cut short, got all {} echoes", echoes);
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stop_with_timeout_interrupts_paced_stream_echo() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // The longest stream allowed, which would run for hours
+    start_stream_echo(&mut client, 1000, 10_000);
+
+    // Stop gives up on it once the drain window is over, without waiting out the pause
+    let started = Instant::now();
+    assert!(
+        !server.stop_with_timeout(Duration::from_millis(200)),
+        "Streaming client should have been force-closed"
+    );
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "Stop took {:?} with a paced stream running",
+        started.elapsed()
+    );
+    assert!(client.receive().is_err(), "Connection should be closed");
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_server_listens_on_ipv4_and_ipv6() {
+    // Set up a dual-stack server in a separate thread
+    let server = Arc::new(Server::new_multi(&["127.0.0.1:0", "[::1]:0"]).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let addrs = server.local_addrs().expect("Failed to read server addresses");
+    assert_eq!(addrs.len(), 2, "Expected one listener per address");
+    assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6(), "Unexpected listener addresses: {:?}", addrs);
+
+    // Connect to each listener and echo through it
+    for addr in addrs {
+        let host = match addr {
+            std::net::SocketAddr::V4(v4) => v4.ip().to_string(),
+            std::net::SocketAddr::V6(v6) => format!("[{}]", v6.ip()), // Bracketed so the port separator is unambiguous
+        };
+        let mut client = client::Client::new(&host, u32::from(addr.port()), 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to {}", addr);
+
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: format!("Via {}", addr),
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                assert_eq!(echo.content, format!("Via {}", addr));
+            }
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_server_stops_after_lifetime_connection_limit() {
+    // Set up a server that serves three connections in total
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .max_lifetime_connections(3)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // The first three clients are served normally
+    let mut clients: Vec<client::Client> = (0..3)
+        .map(|i| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            let message = client_message::Message::PingRequest(PingRequest { nonce: i });
+            assert!(client.send(message).is_ok(), "Failed to send message");
+            match client.receive().expect("Failed to receive response").message {
+                Some(server_message::Message::PongResponse(pong)) => assert_eq!(pong.nonce, i),
+                _ => panic!("Expected PongResponse, but received a different message"),
+            }
+            client
+        })
+        .collect();
+
+    // The fourth is refused straight away
+    let mut refused = client::Client::new("localhost", port, 1000);
+    if refused.connect().is_ok() {
+        assert!(refused.receive().is_err(), "Fourth connection should be closed by the server");
+    }
+
+    // Connected clients keep being served while the server drains
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 10 });
+    assert!(clients[0].send(message).is_ok(), "Failed to send message");
+    assert!(clients[0].receive().is_ok(), "Draining server should still answer connected clients");
+    assert!(!handle.is_finished(), "Server should wait for connected clients before stopping");
+
+    // Once they leave, run returns without anyone calling stop
+    for client in clients.iter_mut() {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+    assert!(
+        wait_for(Duration::from_secs(2), || handle.is_finished()),
+        "Server did not stop after draining"
+    );
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_oversized_length_prefix_drops_connection() {
+    // Set up the server in a separate thread, with the default 1 MB limit
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Claim a 4 GB payload and send only a few bytes of it
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set read timeout");
+    stream
+        .write_all(&[0xFF, 0xFF, 0xFF, 0xFF, 1, 2, 3])
+        .expect("Failed to send oversized header");
+
+    // The server hangs up instead of waiting for (or allocating) the rest
+    let mut buffer = [0u8; 16];
+    match stream.read(&mut buffer) {
+        Ok(bytes_read) => assert_eq!(bytes_read, 0, "Server should not respond to an oversized frame"),
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset, "Unexpected error: {}", e),
+    }
+
+    // Other clients are unaffected
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 3 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Server stopped serving after the oversized frame");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_complete_oversized_frame_is_not_processed() {
+    // Set up a server that allows at most 16 payload bytes
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .max_message_size(16)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Send a whole frame that is over the limit in a single write
+    let payload = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "x".repeat(200),
+        })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set read timeout");
+    stream.write_all(&frame).expect("Failed to send frame");
+
+    // The server hangs up without echoing it
+    let mut buffer = [0u8; 16];
+    match stream.read(&mut buffer) {
+        Ok(bytes_read) => assert_eq!(bytes_read, 0, "Server should not answer an oversized frame"),
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset, "Unexpected error: {}", e),
+    }
+    assert_eq!(server.stats().messages_processed, 0, "Oversized frame should never be decoded");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_nodelay_is_set_on_accepted_sockets() {
+    // Server::new sets TCP_NODELAY by default, nodelay(false) leaves Nagle's algorithm on
+    for (server, expected) in [
+        (create_server(), true),
+        (
+            Arc::new(
+                Server::builder()
+                    .addr("localhost:0")
+                    .nodelay(false)
+                    .build()
+                    .expect("Failed to start server"),
+            ),
+            false,
+        ),
+    ] {
+        let handle = setup_server_thread(server.clone());
+        let port = server_port(&server);
+
+        // Create and connect the client, with a round trip so the server has set up the socket
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: "ping".to_string(),
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        assert!(client.receive().is_ok(), "Failed to receive echo");
+
+        // The accepted socket carries the configured option
+        let snapshot = server.connection_metrics_snapshot();
+        assert_eq!(snapshot.len(), 1, "Expected one connection: {:?}", snapshot);
+        assert_eq!(snapshot[0].nodelay, expected);
+
+        // Disconnect the client
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+
+        // Stop the server and wait for thread to finish
+        server.stop();
+        assert!(
+            handle.join().is_ok(),
+            "Server thread panicked or failed to join"
+        );
+    }
+}
+
+#[cfg(feature = "accept-delay")]
+#[test]
+fn test_accept_delay_exceeds_client_timeout() {
+    // Set up the server in a separate thread, taking 500ms to start serving each connection
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .accept_delay(Duration::from_millis(500))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // The connect itself succeeds: the kernel completes the handshake and parks the connection in the
+    // listen backlog until the delayed accept picks it up
+    let mut client = client::Client::builder("localhost", port)
+        .connect_timeout(Duration::from_millis(100))
+        .read_timeout(Duration::from_millis(100))
+        .build();
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // So the delay shows up as the first request going unanswered within the client's timeout
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 5 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    let err = client
+        .receive()
+        .expect_err("Server answered before the accept delay elapsed");
+    assert!(
+        matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut),
+        "Unexpected error: {}",
+        err
+    );
+
+    // Once the delay has passed the request is served as usual
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let response = loop {
+        match client.receive() {
+            Ok(response) => break response,
+            Err(e) if Instant::now() < deadline && e.kind() == err.kind() => continue,
+            Err(e) => panic!("Server never answered after the accept delay: {}", e),
+        }
+    };
+    match response.message {
+        Some(server_message::Message::PongResponse(pong)) => assert_eq!(pong.nonce, 5),
+        _ => panic!("Expected PongResponse, but received a different message"),
+    }
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_echo() {
+    use std::os::unix::net::UnixStream;
+
+    // Set up the server in a separate thread, listening on a socket file of its own
+    let path = std::env::temp_dir().join(format!("server-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path); // Left over from an earlier run that was killed
+    let server = Arc::new(
+        Server::new_unix(path.to_str().expect("Temp path is not UTF-8")).expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Framing is the same as over TCP
+    let mut stream = UnixStream::connect(&path).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set read timeout");
+    let echo = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "Hello over a Unix socket".to_string(),
+        })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let mut frame = (echo.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&echo);
+    stream.write_all(&frame).expect("Failed to send echo");
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).expect("Failed to read header");
+    let mut response = vec![0u8; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut response).expect("Failed to read payload");
+    match ServerMessage::decode(response.as_slice()).expect("Failed to decode response").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "Hello over a Unix socket");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Dropping the server cleans up its socket file
+    drop(server);
+    assert!(!path.exists(), "Socket file was left behind");
+}
@@ -0,0 +1,1092 @@
+// Tests that touch process-wide state: the capture logger, which sees every server's log lines, the open file
+// limit, ports picked as fixed ones, and latency measured against the wall clock. They get a test binary of their
+// own, so they never overlap the parallel tests in client_test.rs, and run one at a time within it.
+use embedded_recruitment_task::{
+    message::{
+        client_message, server_message, AddRequest, ClientMessage, EchoMessage, PingRequest, ServerMessage, StreamEchoRequest,
+        SubtractRequest,
+    },
+    error::ServerError,
+    handler::{DefaultHandler, MessageHandler},
+    server::{SaturationPolicy, Server},
+};
+use log::Level;
+use prost::Message;
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use serial_test::serial;
+mod client;
+mod logger;
+mod support;
+use support::{assert_ping, create_server, server_port, setup_server_thread, start_saturated_server, wait_for};
+
+#[test]
+#[serial]
+fn test_client_builder_retries_and_read_timeout() {
+    // Find a free port, then only start listening on it a little later
+    let port = {
+        let probe = std::net::TcpListener::bind("localhost:0").expect("Failed to find a free port");
+        u32::from(probe.local_addr().expect("Failed to read probe address").port())
+    };
+    let late_start = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        let server = Arc::new(Server::new(&format!("localhost:{}", port)).expect("Failed to start server"));
+        (server.clone(), setup_server_thread(server))
+    });
+
+    // Connecting keeps retrying until the server is up
+    let mut client = client::Client::builder("localhost", port)
+        .connect_timeout(Duration::from_millis(100))
+        .connect_retries(6, Duration::from_millis(20))
+        .read_timeout(Duration::from_millis(200))
+        .build();
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let (server, handle) = late_start.join().expect("Server start-up thread panicked");
+    assert_ping(&mut client, 1);
+
+    // With nothing to answer, receive gives up after the read timeout instead of hanging
+    let started = Instant::now();
+    assert!(client.receive().is_err(), "Received a response to nothing");
+    assert!(started.elapsed() < Duration::from_secs(1), "receive ignored the read timeout");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+#[serial]
+fn test_concurrent_stops_shut_down_once() {
+    logger::init();
+
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+
+    // A connected client gives stop a thread to join
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut client, 1);
+
+    // Stop the server from four threads at once
+    let stoppers: Vec<_> = (0..4)
+        .map(|_| {
+            let server = server.clone();
+            thread::spawn(move || server.stop())
+        })
+        .collect();
+    for stopper in stoppers {
+        assert!(stopper.join().is_ok(), "A stop call panicked");
+    }
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Exactly one caller did the shutdown, the rest saw it was already stopping
+    let count = |text: &str| {
+        logger::records()
+            .iter()
+            .filter(|record| record.message == text)
+            .count()
+    };
+    assert_eq!(count("Shutdown signal sent."), 1);
+    assert_eq!(count("All client threads joined."), 1);
+    assert_eq!(count("Server was already stopped or not running."), 3);
+}
+
+#[test]
+#[serial]
+fn test_restart_on_same_port_right_after_stop() {
+    // Find a free port, then use it as a fixed one
+    let port = {
+        let server = create_server();
+        server_port(&server)
+    };
+    let addr = format!("localhost:{}", port);
+
+    // Serve a one-shot request, so the server closes first and its side of the connection is left in TIME_WAIT
+    let server = Arc::new(Server::new(&addr).expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = ClientMessage {
+        message: Some(client_message::Message::PingRequest(PingRequest { nonce: 1 })),
+        close_after_response: true,
+        ..Default::default()
+    };
+    assert!(client.send_message(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response");
+    assert!(client.receive().is_err(), "Server should close the connection after its response");
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    drop(server);
+
+    // A new server binds the same port straight away and serves requests
+    let server = Arc::new(Server::new(&addr).expect("Rebinding the same port right after stop failed"));
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the restarted server");
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 2 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Without SO_REUSEADDR the port stays taken while the old server's side of a connection is in TIME_WAIT.
+// The default, with it, is covered by test_restart_on_same_port_right_after_stop.
+#[cfg(target_os = "linux")]
+#[test]
+#[serial]
+fn test_reuse_addr_off_refuses_port_in_time_wait() {
+    // Find a free port, then use it as a fixed one
+    let port = {
+        let server = create_server();
+        server_port(&server)
+    };
+    let addr = format!("localhost:{}", port);
+    let build = || Server::builder().addr(&addr).reuse_addr(false).build();
+
+    // Serve a one-shot request, so the server closes first and leaves TIME_WAIT behind
+    let server = Arc::new(build().expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = ClientMessage {
+        message: Some(client_message::Message::PingRequest(PingRequest { nonce: 1 })),
+        close_after_response: true,
+        ..Default::default()
+    };
+    assert!(client.send_message(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response");
+    assert!(client.receive().is_err(), "Server should close the connection after its response");
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    drop(server);
+
+    match build() {
+        Err(ServerError::Bind(e)) => assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse),
+        Err(e) => panic!("Expected a bind error, got {}", e),
+        Ok(_) => panic!("Port in TIME_WAIT was bound without SO_REUSEADDR"),
+    }
+}
+
+#[test]
+#[serial]
+fn test_partial_header_at_eof_closes_quietly() {
+    logger::init();
+
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Send only half of a length prefix, then close the write side
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set read timeout");
+    stream.write_all(&[0, 0]).expect("Failed to send partial header");
+    stream.shutdown(Shutdown::Write).expect("Failed to shutdown write side");
+
+    // The server should close its side without sending anything back
+    let mut buffer = [0u8; 16];
+    let bytes_read = stream.read(&mut buffer).expect("Server did not close the connection");
+    assert_eq!(bytes_read, 0, "Server should not respond to a partial header");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // The dangling bytes must not be reported as a decode failure or any other error
+    let errors: Vec<String> = logger::records()
+        .into_iter()
+        .filter(|record| record.level == Level::Error)
+        .map(|record| record.message)
+        .collect();
+    assert!(errors.is_empty(), "Unexpected errors logged: {:?}", errors);
+}
+
+#[test]
+#[serial]
+fn test_decode_error_log_names_the_connection() {
+    logger::init();
+
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Frame a payload that is not a valid ClientMessage
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    let garbage = [0xFF, 0xFF, 0xFF];
+    let mut frame = (garbage.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&garbage);
+    stream.write_all(&frame).expect("Failed to send frame");
+
+    let decode_errors = || -> Vec<String> {
+        logger::records()
+            .into_iter()
+            .filter(|record| record.level == Level::Error && record.message.contains("Failed to decode"))
+            .map(|record| record.message)
+            .collect()
+    };
+    assert!(
+        wait_for(Duration::from_secs(1), || !decode_errors().is_empty()),
+        "The decode failure was not logged"
+    );
+
+    // The error says which connection it came from
+    let metrics = server.connection_metrics_snapshot();
+    assert_eq!(metrics.len(), 1, "Expected a single connection: {:?}", metrics);
+    let line = decode_errors().remove(0);
+    assert!(line.contains(&format!("conn={}", metrics[0].id)), "Missing connection id in {}", line);
+    let local_addr = stream.local_addr().expect("Failed to read client address");
+    assert!(line.contains(&format!("peer={}", local_addr)), "Missing peer address in {}", line);
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+#[serial]
+fn test_handled_request_log_names_the_connection() {
+    logger::init();
+
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client, and have the handler answer an echo
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "Logged".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive echo");
+
+    // The line logged for the request says which connection it came from
+    let metrics = server.connection_metrics_snapshot();
+    assert_eq!(metrics.len(), 1, "Expected a single connection: {:?}", metrics);
+    let received: Vec<String> = logger::records()
+        .into_iter()
+        .filter(|record| record.message.contains("Received EchoMessage: Logged"))
+        .map(|record| record.message)
+        .collect();
+    assert_eq!(received.len(), 1, "Expected one line for the request: {:?}", received);
+    let local_addr = client.local_addr().expect("Failed to read client address");
+    let expected = format!("conn={} peer={}", metrics[0].id, local_addr);
+    assert!(received[0].contains(&expected), "Missing connection context in {}", received[0]);
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+#[serial]
+fn test_client_closing_before_response_is_not_an_error() {
+    logger::init();
+
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Ask for a paced stream of echoes, then hang up without reading any of them
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    let request = ClientMessage {
+        message: Some(client_message::Message::StreamEchoRequest(StreamEchoRequest {
+            content: "Nobody is listening".to_string(),
+            count: 5,
+            interval_ms: 20,
+        })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let mut frame = (request.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&request);
+    stream.write_all(&frame).expect("Failed to send request");
+    drop(stream);
+
+    // The server notices on a later write and lets the connection go
+    let closed_early = || {
+        logger::records()
+            .iter()
+            .any(|record| record.message.contains("closed the connection before its response was written"))
+    };
+    assert!(wait_for(Duration::from_secs(2), closed_early), "The early close was not logged");
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 0),
+        "Server still counts the closed client"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // A client walking away is logged as a disconnect, not as an error
+    let errors: Vec<String> = logger::records()
+        .into_iter()
+        .filter(|record| record.level == Level::Error)
+        .map(|record| record.message)
+        .collect();
+    assert!(errors.is_empty(), "Unexpected errors logged: {:?}", errors);
+}
+
+#[test]
+#[serial]
+fn test_write_timeout_closes_client_that_stops_reading() {
+    logger::init();
+
+    // Set up a server that gives up on a blocked write after 200ms
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .write_timeout(Duration::from_millis(200))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Ask for far more echoes than the socket buffers hold, then never read any of them
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    let request = ClientMessage {
+        message: Some(client_message::Message::StreamEchoRequest(StreamEchoRequest {
+            content: "x".repeat(64 * 1024),
+            count: 1000,
+            interval_ms: 0,
+        })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let mut frame = (request.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&request);
+    stream.write_all(&frame).expect("Failed to send request");
+
+    // The server stops waiting for the client to catch up and closes the connection
+    let timed_out = || {
+        logger::records()
+            .iter()
+            .any(|record| record.level == Level::Warn && record.message.contains("write timed out"))
+    };
+    assert!(wait_for(Duration::from_secs(5), timed_out), "The write timeout was not logged");
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 0),
+        "Server is still serving the stalled client"
+    );
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+#[serial]
+fn test_access_log_line_per_request() {
+    logger::init();
+
+    // Set up a server with the access log enabled
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .access_log(true)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Send three different requests
+    let requests = [
+        client_message::Message::EchoMessage(EchoMessage {
+            content: "Audited".to_string(),
+        }),
+        client_message::Message::AddRequest(AddRequest { a: 1, b: 2 }),
+        client_message::Message::SubtractRequest(SubtractRequest { a: 5, b: 3 }),
+    ];
+    for request in requests {
+        assert!(client.send(request).is_ok(), "Failed to send message");
+        assert!(client.receive().is_ok(), "Failed to receive response");
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Exactly one access-log line per request, in order, with every field present
+    let access_lines: Vec<String> = logger::records()
+        .into_iter()
+        .filter(|record| record.target == "access")
+        .map(|record| record.message)
+        .collect();
+    assert_eq!(access_lines.len(), 3, "Expected one access-log line per request: {:?}", access_lines);
+
+    for (line, request_type) in access_lines
+        .iter()
+        .zip(["EchoMessage", "AddRequest", "SubtractRequest"])
+    {
+        assert!(line.contains("conn="), "Missing connection id in {}", line);
+        assert!(line.contains("peer=127.0.0.1:"), "Missing peer address in {}", line);
+        assert!(
+            line.contains(&format!("type={}", request_type)),
+            "Missing message type in {}",
+            line
+        );
+        assert!(line.contains("request_bytes="), "Missing request size in {}", line);
+        assert!(line.contains("response_bytes="), "Missing response size in {}", line);
+        assert!(line.contains("duration_us="), "Missing duration in {}", line);
+    }
+}
+
+#[test]
+#[serial]
+fn test_allowlist_rejects_other_peers() {
+    logger::init();
+
+    // Set up a server on loopback that only lets a different address in, and stops after two served connections
+    let server = Arc::new(
+        Server::builder()
+            .addr("127.0.0.1:0")
+            .allowlist(vec!["10.0.0.1".parse().unwrap()])
+            .max_lifetime_connections(2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Connections from 127.0.0.1 are closed without being served
+    for _ in 0..2 {
+        let mut client = client::Client::new("127.0.0.1", server_port(&server), 1000);
+        if client.connect().is_ok() {
+            assert!(client.receive().is_err(), "Disallowed connection should be closed by the server");
+        }
+    }
+    let rejections = || {
+        logger::records()
+            .iter()
+            .filter(|record| record.level == Level::Warn && record.message.contains("is not on the allowlist"))
+            .count()
+    };
+    assert!(wait_for(Duration::from_secs(1), || rejections() == 2), "Disallowed connections were not logged");
+    assert_eq!(server.stats().connections_accepted, 2);
+    assert_eq!(server.client_count(), 0, "Disallowed connection should not be served");
+
+    // Rejected peers don't use up the lifetime limit, so they can't make the server stop itself: a third one is
+    // still checked against the allowlist rather than refused for the limit
+    let mut client = client::Client::new("127.0.0.1", server_port(&server), 1000);
+    if client.connect().is_ok() {
+        assert!(client.receive().is_err(), "Disallowed connection should be closed by the server");
+    }
+    assert!(
+        wait_for(Duration::from_secs(1), || rejections() == 3),
+        "Rejected connections counted towards the lifetime limit"
+    );
+    assert!(server.is_running(), "Rejected connections counted towards the lifetime limit");
+    assert!(!handle.is_finished(), "Server stopped after only rejecting connections");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+#[serial]
+fn test_startup_self_test_runs_before_serving() {
+    logger::init();
+
+    // Set up a server that checks its own round trip before accepting clients
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .self_test(true)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // The probe connection is closed once it has its pong
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 0),
+        "Self-test connection was not closed"
+    );
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let client_addr = client.local_addr().expect("Failed to read client address");
+
+    // Regular requests are served once the self-test has passed
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "After self-test".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "After self-test");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    let metrics = server.connection_metrics_snapshot();
+    assert_eq!(metrics.len(), 1, "Expected a single connection: {:?}", metrics);
+    assert_eq!(metrics[0].id, 0, "The probe should not use up a connection id");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // The self-test has to pass before the test client is accepted
+    let messages: Vec<String> = logger::records()
+        .into_iter()
+        .map(|record| record.message)
+        .collect();
+    let passed = messages
+        .iter()
+        .position(|message| message.starts_with("Startup self-test passed"))
+        .expect("Self-test did not report success");
+    let accepted = messages
+        .iter()
+        .position(|message| *message == format!("New client connected: {}", client_addr))
+        .expect("Test client was never accepted");
+    assert!(passed < accepted, "Client was accepted before the self-test passed");
+}
+
+// Custom handler that panics on one particular echo, standing in for a buggy handler
+struct PanickingHandler;
+
+impl MessageHandler for PanickingHandler {
+    fn handle(&self, msg: ClientMessage) -> Option<ServerMessage> {
+        match &msg.message {
+            Some(client_message::Message::EchoMessage(echo)) if echo.content == "panic" => {
+                panic!("PanickingHandler was asked to panic")
+            }
+            _ => DefaultHandler.handle(msg),
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_handler_panic_only_ends_its_connection() {
+    logger::init();
+
+    // Set up a server with the panicking handler
+    let server = Arc::new(
+        Server::with_handler("localhost:0", Box::new(PanickingHandler)).expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Create and connect both clients
+    let mut victim = client::Client::new("localhost", port, 1000);
+    assert!(victim.connect().is_ok(), "Failed to connect to the server");
+    let mut bystander = client::Client::new("localhost", port, 1000);
+    assert!(bystander.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut bystander, 1);
+
+    // The panicking request gets no answer and its connection is closed
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "panic".to_string(),
+    });
+    assert!(victim.send(message).is_ok(), "Failed to send message");
+    assert!(victim.receive().is_err(), "A panicked request should not be answered");
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 1),
+        "Panicked connection is still counted"
+    );
+
+    // The panic is logged with the connection it happened on
+    let panics: Vec<String> = logger::records()
+        .into_iter()
+        .filter(|record| record.level == Level::Error && record.message.contains("panicked"))
+        .map(|record| record.message)
+        .collect();
+    assert_eq!(panics.len(), 1, "Expected one panic record: {:?}", panics);
+    assert!(panics[0].contains("conn="), "Missing connection id in {}", panics[0]);
+    assert!(panics[0].contains("PanickingHandler was asked to panic"), "Missing panic message in {}", panics[0]);
+
+    // Other clients, old and new, are still served
+    assert_ping(&mut bystander, 2);
+    let mut newcomer = client::Client::new("localhost", port, 1000);
+    assert!(newcomer.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut newcomer, 3);
+
+    // Disconnect the clients
+    for client in [&mut bystander, &mut newcomer] {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Custom handler whose "stall" echo blocks until the test releases it, standing in for a stuck handler
+struct StallingHandler {
+    stalled: Arc<std::sync::atomic::AtomicBool>, // Set once a request is stuck in the handler
+    release: Arc<std::sync::atomic::AtomicBool>, // Set by the test to let it finish
+}
+
+impl MessageHandler for StallingHandler {
+    fn handle(&self, msg: ClientMessage) -> Option<ServerMessage> {
+        if let Some(client_message::Message::EchoMessage(echo)) = &msg.message {
+            if echo.content == "stall" {
+                self.stalled.store(true, std::sync::atomic::Ordering::SeqCst);
+                while !self.release.load(std::sync::atomic::Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+        DefaultHandler.handle(msg)
+    }
+}
+
+#[test]
+#[serial]
+fn test_stop_gives_up_on_stuck_client_thread() {
+    logger::init();
+
+    // Set up a server that waits at most 200ms for each client thread
+    let stalled = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let release = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let handler = StallingHandler {
+        stalled: stalled.clone(),
+        release: release.clone(),
+    };
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .handler(Box::new(handler))
+            .join_timeout(Duration::from_millis(200))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Get one client thread stuck in the handler
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "stall".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(
+        wait_for(Duration::from_secs(1), || stalled.load(std::sync::atomic::Ordering::SeqCst)),
+        "Request never reached the handler"
+    );
+
+    // Stop returns after the join timeout instead of waiting for the handler
+    let started = Instant::now();
+    server.stop();
+    assert!(
+        started.elapsed() < Duration::from_secs(1),
+        "stop waited {:?} for a stuck thread",
+        started.elapsed()
+    );
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert!(
+        logger::records()
+            .iter()
+            .any(|record| record.message.contains("did not finish within")),
+        "The stuck thread was not reported"
+    );
+
+    // Let the stuck thread finish so it doesn't outlive the test
+    release.store(true, std::sync::atomic::Ordering::SeqCst);
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 0),
+        "Stuck client thread never finished"
+    );
+}
+
+#[test]
+#[serial]
+fn test_saturation_policy_block() {
+    logger::init();
+    let (server, handle, port, mut clients) = start_saturated_server(SaturationPolicy::Block);
+
+    // The next connection is left waiting rather than rejected
+    let mut extra = client::Client::new("localhost", port, 1000);
+    assert!(extra.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 2 });
+    assert!(extra.send(message).is_ok(), "Failed to send message");
+    let held = || {
+        logger::records()
+            .iter()
+            .any(|record| record.message.contains("Worker queue full, holding"))
+    };
+    assert!(wait_for(Duration::from_secs(1), held), "Connection was not held back");
+    assert_eq!(server.stats().rejected_connections, 0, "Blocking policy should not reject");
+    assert_eq!(server.stats().queued_connections, 1, "Queue should stay at its cap");
+
+    // Once the clients ahead of it leave, it is served
+    for client in clients.iter_mut() {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+    match extra.receive().expect("Blocked connection was never served").message {
+        Some(server_message::Message::PongResponse(pong)) => assert_eq!(pong.nonce, 2),
+        _ => panic!("Expected PongResponse, but received a different message"),
+    }
+    assert!(
+        extra.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+#[serial]
+fn test_connections_closed_before_accept_are_dropped_quietly() {
+    logger::init();
+
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let addr = server.local_addr().expect("Failed to read server address");
+
+    // Connect and hang up straight away, many times over
+    for _ in 0..50 {
+        let stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+        drop(stream);
+    }
+
+    // Most of them are gone before the accept loop gets to them and never get a handler
+    assert!(
+        wait_for(Duration::from_secs(1), || server.stats().dead_on_accept > 0),
+        "No dead connections were detected: {:?}",
+        server.stats()
+    );
+    assert!(
+        wait_for(Duration::from_secs(1), || server.client_count() == 0),
+        "Aborted connections should not stay registered"
+    );
+
+    // The server still serves a real client afterwards
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 9 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Aborted connections are not worth more than a debug line
+    let noisy: Vec<String> = logger::records()
+        .into_iter()
+        .filter(|record| record.level <= Level::Warn)
+        .map(|record| record.message)
+        .collect();
+    assert!(noisy.is_empty(), "Unexpected warnings or errors logged: {:?}", noisy);
+}
+
+#[test]
+#[serial]
+fn test_self_stop_flushes_logs() {
+    logger::init();
+
+    // Set up a server that stops by itself after one connection
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .max_lifetime_connections(1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Serve one request, then leave
+    let mut client = client::Client::new("localhost", server_port(&server), 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 1 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    assert!(
+        wait_for(Duration::from_secs(2), || handle.is_finished()),
+        "Server did not stop after its lifetime limit"
+    );
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Everything up to the final line had been flushed by the time run returned
+    let flushed: Vec<String> = logger::flushed_records()
+        .into_iter()
+        .map(|record| record.message)
+        .collect();
+    assert!(flushed.iter().any(|message| message.ends_with("] Client disconnected.")), "Client thread's last record was not flushed");
+    assert_eq!(
+        flushed.last().map(String::as_str),
+        Some("Lifetime connection limit served, server stopped."),
+        "Final shutdown record was not flushed"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+#[serial]
+fn test_accept_recovers_from_descriptor_exhaustion() {
+    logger::init();
+
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+    let addr = server.local_addr().expect("Failed to read server address"); // Resolved now, lookups need descriptors
+
+    // Lower the descriptor limit to just above what is open, then use up the rest
+    let mut original = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut original) }, 0, "getrlimit failed");
+    let probe = std::fs::File::open("/dev/null").expect("Failed to open /dev/null");
+    let lowered = libc::rlimit {
+        rlim_cur: (std::os::fd::AsRawFd::as_raw_fd(&probe) as libc::rlim_t + 16).min(original.rlim_cur),
+        rlim_max: original.rlim_max,
+    };
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lowered) }, 0, "setrlimit failed");
+    // Puts the original limit back even if an assertion below fails, so later tests aren't starved
+    struct RestoreLimit(libc::rlimit);
+    impl Drop for RestoreLimit {
+        fn drop(&mut self) {
+            unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &self.0) };
+        }
+    }
+    let limit = RestoreLimit(original);
+    let mut hoard = vec![probe];
+    while let Ok(file) = std::fs::File::open("/dev/null") {
+        hoard.push(file);
+    }
+
+    // The blocked accept already holds a descriptor, so free one for our end and one for the server's
+    // clone of the stream. The next accept then has none left and keeps failing.
+    hoard.truncate(hoard.len() - 2);
+    let mut first = TcpStream::connect(addr).expect("Failed to connect to the server");
+    thread::sleep(Duration::from_millis(200)); // Long enough for several failed accepts
+
+    // The connection accepted before the limit was hit is served throughout
+    first
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set read timeout");
+    let ping = ClientMessage {
+        message: Some(client_message::Message::PingRequest(PingRequest { nonce: 1 })),
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let mut frame = (ping.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&ping);
+    first.write_all(&frame).expect("Failed to send ping");
+    let mut header = [0u8; 4];
+    first.read_exact(&mut header).expect("Failed to read header");
+    let mut response = vec![0u8; u32::from_be_bytes(header) as usize];
+    first.read_exact(&mut response).expect("Failed to read payload");
+    match ServerMessage::decode(response.as_slice()).expect("Failed to decode response").message {
+        Some(server_message::Message::PongResponse(pong)) => assert_eq!(pong.nonce, 1),
+        _ => panic!("Expected PongResponse, but received a different message"),
+    }
+
+    // Once descriptors are free again connections are accepted as usual
+    drop(hoard);
+    drop(limit);
+    let mut second = client::Client::new("localhost", port, 1000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    assert_ping(&mut second, 2);
+
+    // Disconnect the clients
+    drop(first);
+    assert!(
+        second.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // The exhaustion was reported once, backed off rather than logged on every retry
+    let records = logger::records();
+    let warnings = records
+        .iter()
+        .filter(|record| record.message.starts_with("Out of resources accepting connections"))
+        .count();
+    assert_eq!(warnings, 1, "Expected a single exhaustion warning");
+    let retries = records
+        .iter()
+        .filter(|record| record.message.starts_with("Still out of resources"))
+        .count();
+    assert!(retries < 10, "Accept retried {} times in 200ms, backoff is not growing", retries);
+    assert!(
+        records.iter().any(|record| record.message.starts_with("Accepting connections again")),
+        "Recovery was not logged"
+    );
+    assert!(
+        !records.iter().any(|record| record.level == Level::Error),
+        "Descriptor exhaustion should not be logged as an error"
+    );
+}
+
+#[test]
+#[serial]
+fn test_accept_latency_without_polling() {
+    // Set up the server in a separate thread
+    let server = create_server();
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    // Time from connect() to the server having accepted and registered the client
+    let iterations: u32 = 20;
+    let mut total = Duration::ZERO;
+    for _ in 0..iterations {
+        let mut client = client::Client::new("localhost", port, 1000);
+        let start = Instant::now();
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        while server.client_count() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(1), "Client was never accepted");
+            std::hint::spin_loop();
+        }
+        total += start.elapsed();
+
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+        assert!(
+            wait_for(Duration::from_secs(1), || server.client_count() == 0),
+            "Client did not leave"
+        );
+    }
+
+    // A 10ms accept poll would average around 5ms; a blocking accept is far below that
+    let average = total / iterations;
+    assert!(average < Duration::from_millis(2), "Average accept latency {:?}", average);
+
+    // Stop must still interrupt the blocking accept promptly
+    let stop_started = Instant::now();
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert!(
+        stop_started.elapsed() < Duration::from_millis(500),
+        "Stop took {:?}",
+        stop_started.elapsed()
+    );
+}
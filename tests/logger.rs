@@ -0,0 +1,65 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex, Once,
+};
+
+// One captured log line
+#[derive(Clone, Debug)]
+pub struct LogRecord {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+// Test logger that records every log line so tests can assert on what the server logged
+struct CaptureLogger {
+    records: Mutex<Vec<LogRecord>>,
+    flushed_len: AtomicUsize, // How many records had been logged at the last flush
+}
+
+static LOGGER: CaptureLogger = CaptureLogger {
+    records: Mutex::new(Vec::new()),
+    flushed_len: AtomicUsize::new(0),
+};
+static INIT: Once = Once::new();
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.records.lock().unwrap().push(LogRecord {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+    }
+
+    fn flush(&self) {
+        let len = self.records.lock().unwrap().len();
+        self.flushed_len.store(len, Ordering::SeqCst);
+    }
+}
+
+// install the capturing logger (once per test binary) and discard anything already recorded
+pub fn init() {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).expect("Another logger is already installed");
+        log::set_max_level(LevelFilter::Trace);
+    });
+    LOGGER.records.lock().unwrap().clear();
+    LOGGER.flushed_len.store(0, Ordering::SeqCst);
+}
+
+// everything logged since the last init
+pub fn records() -> Vec<LogRecord> {
+    LOGGER.records.lock().unwrap().clone()
+}
+
+// the records that had been logged when the logger was last flushed, as a file sink would have them on disk
+pub fn flushed_records() -> Vec<LogRecord> {
+    let records = LOGGER.records.lock().unwrap();
+    records[..LOGGER.flushed_len.load(Ordering::SeqCst).min(records.len())].to_vec()
+}
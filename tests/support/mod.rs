@@ -0,0 +1,81 @@
+// Helpers shared by the test binaries
+use embedded_recruitment_task::{
+    message::{client_message, server_message, PingRequest},
+    server::{SaturationPolicy, Server},
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use crate::client;
+
+pub fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
+    let running = server.clone();
+    let handle = thread::spawn(move || {
+        server.run().expect("Server encountered an error");
+    });
+    running.wait_until_ready(Duration::from_secs(1)); // Don't let the test call stop() before run() starts
+    handle
+}
+
+pub fn create_server() -> Arc<Server> {
+    // Port 0 lets the OS pick a free port, so tests never collide on a fixed one
+    Arc::new(Server::new("localhost:0").expect("Failed to start server"))
+}
+
+// poll until the condition holds or the timeout runs out, returning whether it held
+pub fn wait_for(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    condition()
+}
+
+pub fn server_port(server: &Server) -> u32 {
+    u32::from(server.local_addr().expect("Failed to read server address").port())
+}
+
+// a one-worker server with room for one queued connection, with the worker busy and the queue full
+pub fn start_saturated_server(policy: SaturationPolicy) -> (Arc<Server>, JoinHandle<()>, u32, Vec<client::Client>) {
+    let server = Arc::new(
+        Server::builder()
+            .addr("localhost:0")
+            .workers(1)
+            .max_queued_connections(1)
+            .saturation_policy(policy)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let port = server_port(&server);
+
+    let mut busy = client::Client::new("localhost", port, 1000);
+    assert!(busy.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::PingRequest(PingRequest { nonce: 1 });
+    assert!(busy.send(message).is_ok(), "Failed to send message");
+    assert!(busy.receive().is_ok(), "Failed to receive response");
+
+    let mut queued = client::Client::new("localhost", port, 1000);
+    assert!(queued.connect().is_ok(), "Failed to connect to the server");
+    assert!(
+        wait_for(Duration::from_secs(1), || server.stats().queued_connections == 1),
+        "Expected one queued connection, got {:?}",
+        server.stats()
+    );
+    (server, handle, port, vec![busy, queued])
+}
+
+// send a ping and wait for its pong
+pub fn assert_ping(client: &mut client::Client, nonce: u64) {
+    let message = client_message::Message::PingRequest(PingRequest { nonce });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::PongResponse(pong)) => assert_eq!(pong.nonce, nonce),
+        _ => panic!("Expected PongResponse, but received a different message"),
+    }
+}